@@ -0,0 +1,672 @@
+use std::mem;
+
+use cpu::CYCLES_PER_SECOND;
+
+/// The frame sequencer runs at 512 Hz.
+const FRAME_SEQUENCER_CYCLES: u32 = 8192;
+/// Samples kept for the frontend to pick up, past that new ones are
+/// dropped, in stereo frames.
+const MAX_FRAMES: usize = 16_384;
+
+/// Waveforms of the square channels, one bit per step.
+const DUTY_CYCLES: [u8; 4] =
+    [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+/// Right shifts applied to the wave samples by NR32.
+const WAVE_SHIFTS: [u8; 4] = [4, 0, 1, 2];
+
+/// Bits that read back as 1 in NR10 to NR52, most of them are write only.
+const READ_MASKS: [u8; 0x17] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+];
+
+/// Offsets of the registers, relative to NR10 (0xFF10).
+const NR10: usize = 0x00;
+const NR11: usize = 0x01;
+const NR12: usize = 0x02;
+const NR14: usize = 0x04;
+const NR21: usize = 0x06;
+const NR22: usize = 0x07;
+const NR24: usize = 0x09;
+const NR30: usize = 0x0A;
+const NR31: usize = 0x0B;
+const NR32: usize = 0x0C;
+const NR34: usize = 0x0E;
+const NR41: usize = 0x10;
+const NR42: usize = 0x11;
+const NR43: usize = 0x12;
+const NR44: usize = 0x13;
+const NR50: usize = 0x14;
+const NR51: usize = 0x15;
+const NR52: usize = 0x16;
+
+/// Volume envelope of the square and noise channels, set by NRx2.
+#[derive(Default)]
+struct Envelope {
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn trigger(&mut self, nrx2: u8) {
+        self.volume = nrx2 >> 4;
+        self.timer = nrx2 & 0x07;
+    }
+
+    fn clock(&mut self, nrx2: u8) {
+        let pace = nrx2 & 0x07;
+        if pace == 0 {
+            return;
+        }
+
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = pace;
+            if nrx2 & 0x08 != 0 {
+                self.volume = (self.volume + 1).min(15);
+            } else {
+                self.volume = self.volume.saturating_sub(1);
+            }
+        }
+    }
+}
+
+/// Counts `cycles` down on `timer`, reloading it with `period` each time it
+/// runs out, and returns how many times it did.
+fn run_timer(timer: &mut u32, cycles: u32, period: u32) -> u32 {
+    let mut cycles = cycles;
+    let mut expired = 0;
+    while cycles >= *timer {
+        cycles -= *timer;
+        *timer = period;
+        expired += 1;
+    }
+    *timer -= cycles;
+    expired
+}
+
+/// Channels 1 and 2.
+#[derive(Default)]
+struct Square {
+    enabled: bool,
+    length: u16,
+    timer: u32,
+    /// Position in the 8 steps waveform.
+    step: u8,
+    envelope: Envelope,
+}
+
+/// Channel 3, playing the 32 samples of the wave RAM.
+#[derive(Default)]
+struct Wave {
+    enabled: bool,
+    length: u16,
+    timer: u32,
+    position: u8,
+    /// Last sample read from the wave RAM.
+    sample: u8,
+}
+
+/// Channel 4, a linear feedback shift register.
+#[derive(Default)]
+struct Noise {
+    enabled: bool,
+    length: u16,
+    timer: u32,
+    lfsr: u16,
+    envelope: Envelope,
+}
+
+/// Frequency sweep of channel 1.
+#[derive(Default)]
+struct Sweep {
+    enabled: bool,
+    timer: u8,
+    /// Frequency the sweep works on.
+    shadow: u16,
+}
+
+/// Stereo output resampled to the host rate.
+struct Output {
+    /// T-cycles between two host samples.
+    cycles_per_sample: f64,
+    sample_cycles: f64,
+    /// Output summed over the T-cycles of the sample being built.
+    left_sum: f32,
+    right_sum: f32,
+    summed_cycles: u32,
+    /// High pass filters of both outputs, removing the DC offset like the
+    /// capacitors of the hardware.
+    capacitors: [f32; 2],
+    /// Interleaved stereo samples, from -1.0 to 1.0.
+    samples: Vec<f32>,
+    /// Whether samples past `MAX_FRAMES` are dropped.
+    capped: bool,
+}
+
+impl Output {
+    /// Adds the output of one M-cycle.
+    fn add(&mut self, (left, right): (f32, f32)) {
+        self.left_sum += left * 4.0;
+        self.right_sum += right * 4.0;
+        self.summed_cycles += 4;
+
+        self.sample_cycles += 4.0;
+        if self.sample_cycles >= self.cycles_per_sample {
+            self.sample_cycles -= self.cycles_per_sample;
+            self.push_sample();
+        }
+    }
+
+    /// Turns the output summed since the last sample into a new one.
+    fn push_sample(&mut self) {
+        let cycles = self.summed_cycles.max(1) as f32;
+        let charge = 0.999_958f32.powf(self.cycles_per_sample as f32);
+        let inputs = [self.left_sum / cycles, self.right_sum / cycles];
+
+        if !self.capped || self.samples.len() < MAX_FRAMES * 2 {
+            for (capacitor, input) in self.capacitors.iter_mut().zip(&inputs) {
+                let output = input - *capacitor;
+                *capacitor = input - output * charge;
+                self.samples.push(output);
+            }
+        }
+
+        self.left_sum = 0.0;
+        self.right_sum = 0.0;
+        self.summed_cycles = 0;
+    }
+
+    fn new(sample_rate: f64, capped: bool) -> Output {
+        Output {
+            cycles_per_sample: CYCLES_PER_SECOND as f64 / sample_rate,
+            sample_cycles: 0.0,
+            left_sum: 0.0,
+            right_sum: 0.0,
+            summed_cycles: 0,
+            capacitors: [0.0; 2],
+            samples: Vec::new(),
+            capped,
+        }
+    }
+}
+
+/// Output kept at a fixed rate, apart from the one the frontend plays, so
+/// it only depends on what the game does.
+pub struct Recording {
+    mix: Output,
+    /// Each channel alone, panned and scaled like in the mix.
+    stems: Vec<Output>,
+}
+
+impl Recording {
+    /// Takes the mix recorded so far, interleaved as left and right.
+    pub fn take_mix(&mut self) -> Vec<f32> {
+        mem::take(&mut self.mix.samples)
+    }
+
+    /// Takes the stems recorded so far, from channel 1 to 4, or nothing
+    /// when they are not recorded.
+    pub fn take_stems(&mut self) -> Vec<Vec<f32>> {
+        self.stems
+            .iter_mut()
+            .map(|stem| mem::take(&mut stem.samples))
+            .collect()
+    }
+
+    /// Records at `sample_rate` Hz, with a stem per channel if `stems`.
+    pub fn new(sample_rate: u32, stems: bool) -> Recording {
+        let output = || Output::new(sample_rate as f64, false);
+        Recording {
+            mix: output(),
+            stems: if stems {
+                (0..4).map(|_| output()).collect()
+            } else {
+                Vec::new()
+            },
+        }
+    }
+}
+
+/// Audio processing unit, owning NR10-NR52 (0xFF10-0xFF26) and the wave RAM
+/// (0xFF30-0xFF3F).
+pub struct Apu {
+    registers: [u8; 0x17],
+    wave_ram: [u8; 0x10],
+    square1: Square,
+    square2: Square,
+    wave: Wave,
+    noise: Noise,
+    sweep: Sweep,
+    /// Step of the frame sequencer, from 0 to 7.
+    sequencer_step: u8,
+    sequencer_cycles: u32,
+    /// Samples played by the frontend.
+    output: Output,
+    recording: Option<Recording>,
+}
+
+impl Apu {
+    /// Reads the register at 0xFF10 + `register`.
+    pub fn read_register(&self, register: u16) -> u8 {
+        let register = register as usize;
+        match register {
+            NR52 => {
+                let channels = [
+                    self.square1.enabled,
+                    self.square2.enabled,
+                    self.wave.enabled,
+                    self.noise.enabled,
+                ];
+                let status = channels
+                    .iter()
+                    .enumerate()
+                    .filter(|&(_, enabled)| *enabled)
+                    .fold(0, |status, (channel, _)| status | 1 << channel);
+                self.registers[NR52] & 0x80 | READ_MASKS[NR52] | status
+            }
+            0x00..=0x15 => self.registers[register] | READ_MASKS[register],
+            0x20..=0x2F => self.wave_ram[register - 0x20],
+            _ => 0xFF,
+        }
+    }
+
+    /// Writes the register at 0xFF10 + `register`.
+    pub fn write_register(&mut self, register: u16, value: u8) {
+        let register = register as usize;
+        if let 0x20..=0x2F = register {
+            self.wave_ram[register - 0x20] = value;
+            return;
+        }
+
+        if register == NR52 {
+            self.write_power(value);
+            return;
+        }
+
+        // While powered off, only NR52 and the wave RAM can be written.
+        if !self.powered() || register > NR52 {
+            return;
+        }
+
+        self.registers[register] = value;
+        match register {
+            NR11 => self.square1.length = 64 - (value & 0x3F) as u16,
+            NR12 if value & 0xF8 == 0 => self.square1.enabled = false,
+            NR14 if value & 0x80 != 0 => self.trigger_square1(),
+            NR21 => self.square2.length = 64 - (value & 0x3F) as u16,
+            NR22 if value & 0xF8 == 0 => self.square2.enabled = false,
+            NR24 if value & 0x80 != 0 => self.trigger_square2(),
+            NR30 if value & 0x80 == 0 => self.wave.enabled = false,
+            NR31 => self.wave.length = 256 - value as u16,
+            NR34 if value & 0x80 != 0 => self.trigger_wave(),
+            NR41 => self.noise.length = 64 - (value & 0x3F) as u16,
+            NR42 if value & 0xF8 == 0 => self.noise.enabled = false,
+            NR44 if value & 0x80 != 0 => self.trigger_noise(),
+            _ => {}
+        }
+    }
+
+    fn powered(&self) -> bool {
+        self.registers[NR52] & 0x80 != 0
+    }
+
+    /// Powering off clears every register and silences the channels.
+    fn write_power(&mut self, value: u8) {
+        if value & 0x80 == 0 {
+            self.registers = [0; 0x17];
+            self.square1 = Square::default();
+            self.square2 = Square::default();
+            self.wave = Wave::default();
+            self.noise = Noise::default();
+            self.sweep = Sweep::default();
+        } else if !self.powered() {
+            self.registers[NR52] = 0x80;
+            self.sequencer_step = 0;
+            self.sequencer_cycles = 0;
+        }
+    }
+
+    /// Frequency of the channel whose NRx3 is at `nrx3`.
+    fn frequency(&self, nrx3: usize) -> u16 {
+        (self.registers[nrx3 + 1] as u16 & 0x07) << 8
+            | self.registers[nrx3] as u16
+    }
+
+    fn trigger_square1(&mut self) {
+        let period = (2048 - self.frequency(NR14 - 1) as u32) * 4;
+        trigger_square(&mut self.square1, self.registers[NR12], period);
+        self.trigger_sweep();
+    }
+
+    fn trigger_square2(&mut self) {
+        let period = (2048 - self.frequency(NR24 - 1) as u32) * 4;
+        trigger_square(&mut self.square2, self.registers[NR22], period);
+    }
+
+    fn trigger_wave(&mut self) {
+        let period = (2048 - self.frequency(NR34 - 1) as u32) * 2;
+        let wave = &mut self.wave;
+        wave.enabled = self.registers[NR30] & 0x80 != 0;
+        if wave.length == 0 {
+            wave.length = 256;
+        }
+        wave.timer = period;
+        wave.position = 0;
+    }
+
+    fn trigger_noise(&mut self) {
+        let nr42 = self.registers[NR42];
+        let period = self.noise_period();
+        let noise = &mut self.noise;
+        noise.enabled = nr42 & 0xF8 != 0;
+        if noise.length == 0 {
+            noise.length = 64;
+        }
+        noise.timer = period;
+        noise.lfsr = 0x7FFF;
+        noise.envelope.trigger(nr42);
+    }
+
+    fn noise_period(&self) -> u32 {
+        let nr43 = self.registers[NR43];
+        NOISE_DIVISORS[(nr43 & 0x07) as usize] << (nr43 >> 4)
+    }
+
+    fn trigger_sweep(&mut self) {
+        let nr10 = self.registers[NR10];
+        let pace = nr10 >> 4 & 0x07;
+        let shift = nr10 & 0x07;
+
+        self.sweep.shadow = self.frequency(NR14 - 1);
+        self.sweep.timer = if pace == 0 { 8 } else { pace };
+        self.sweep.enabled = pace != 0 || shift != 0;
+        // The overflow check also happens right away.
+        if shift != 0 {
+            self.sweep_frequency();
+        }
+    }
+
+    /// Next frequency of the sweep, turning channel 1 off if it overflows.
+    fn sweep_frequency(&mut self) -> u16 {
+        let nr10 = self.registers[NR10];
+        let delta = self.sweep.shadow >> (nr10 & 0x07);
+        let frequency = if nr10 & 0x08 != 0 {
+            self.sweep.shadow - delta
+        } else {
+            self.sweep.shadow + delta
+        };
+
+        if frequency > 2047 {
+            self.square1.enabled = false;
+        }
+        frequency
+    }
+
+    fn clock_sweep(&mut self) {
+        let nr10 = self.registers[NR10];
+        let pace = nr10 >> 4 & 0x07;
+
+        self.sweep.timer = self.sweep.timer.saturating_sub(1);
+        if self.sweep.timer > 0 {
+            return;
+        }
+        self.sweep.timer = if pace == 0 { 8 } else { pace };
+
+        if !self.sweep.enabled || pace == 0 {
+            return;
+        }
+        let frequency = self.sweep_frequency();
+        if frequency <= 2047 && nr10 & 0x07 != 0 {
+            self.sweep.shadow = frequency;
+            self.registers[NR14 - 1] = frequency as u8;
+            self.registers[NR14] =
+                self.registers[NR14] & 0xF8 | (frequency >> 8) as u8;
+            self.sweep_frequency();
+        }
+    }
+
+    fn clock_lengths(&mut self) {
+        let registers = self.registers;
+        clock_length(
+            &mut self.square1.length,
+            &mut self.square1.enabled,
+            registers[NR14],
+        );
+        clock_length(
+            &mut self.square2.length,
+            &mut self.square2.enabled,
+            registers[NR24],
+        );
+        clock_length(
+            &mut self.wave.length,
+            &mut self.wave.enabled,
+            registers[NR34],
+        );
+        clock_length(
+            &mut self.noise.length,
+            &mut self.noise.enabled,
+            registers[NR44],
+        );
+    }
+
+    fn clock_envelopes(&mut self) {
+        self.square1.envelope.clock(self.registers[NR12]);
+        self.square2.envelope.clock(self.registers[NR22]);
+        self.noise.envelope.clock(self.registers[NR42]);
+    }
+
+    /// Steps 0, 2, 4 and 6 clock the lengths, 2 and 6 the sweep and 7 the
+    /// envelopes.
+    fn clock_sequencer(&mut self) {
+        match self.sequencer_step {
+            0 | 4 => self.clock_lengths(),
+            2 | 6 => {
+                self.clock_lengths();
+                self.clock_sweep();
+            }
+            7 => self.clock_envelopes(),
+            _ => {}
+        }
+        self.sequencer_step = (self.sequencer_step + 1) % 8;
+    }
+
+    /// Advances the channel timers by `cycles` T-cycles.
+    fn run_channels(&mut self, cycles: u32) {
+        let period = (2048 - self.frequency(NR14 - 1) as u32) * 4;
+        let steps = run_timer(&mut self.square1.timer, cycles, period);
+        self.square1.step = (self.square1.step + steps as u8) % 8;
+
+        let period = (2048 - self.frequency(NR24 - 1) as u32) * 4;
+        let steps = run_timer(&mut self.square2.timer, cycles, period);
+        self.square2.step = (self.square2.step + steps as u8) % 8;
+
+        let period = (2048 - self.frequency(NR34 - 1) as u32) * 2;
+        for _ in 0..run_timer(&mut self.wave.timer, cycles, period) {
+            let wave = &mut self.wave;
+            wave.position = (wave.position + 1) % 32;
+            let byte = self.wave_ram[wave.position as usize / 2];
+            wave.sample = if wave.position & 0x01 == 0 {
+                byte >> 4
+            } else {
+                byte & 0x0F
+            };
+        }
+
+        let period = self.noise_period();
+        let wide = self.registers[NR43] & 0x08 == 0;
+        for _ in 0..run_timer(&mut self.noise.timer, cycles, period) {
+            let lfsr = self.noise.lfsr;
+            let bit = (lfsr ^ lfsr >> 1) & 0x01;
+            let mut lfsr = lfsr >> 1 | bit << 14;
+            if !wide {
+                lfsr = lfsr & !0x40 | bit << 6;
+            }
+            self.noise.lfsr = lfsr;
+        }
+    }
+
+    /// Output of each channel's DAC, from -1.0 to 1.0, or 0.0 with the DAC
+    /// off.
+    pub fn channel_outputs(&self) -> [f32; 4] {
+        let square = |square: &Square, nrx1: u8, nrx2: u8| {
+            if nrx2 & 0xF8 == 0 {
+                return None;
+            }
+            let duty = DUTY_CYCLES[(nrx1 >> 6) as usize];
+            let high = square.enabled && duty >> square.step & 0x01 != 0;
+            Some(if high { square.envelope.volume } else { 0 })
+        };
+
+        let wave = if self.registers[NR30] & 0x80 == 0 {
+            None
+        } else if self.wave.enabled {
+            let shift =
+                WAVE_SHIFTS[(self.registers[NR32] >> 5 & 0x03) as usize];
+            Some(self.wave.sample >> shift)
+        } else {
+            Some(0)
+        };
+
+        let noise = if self.registers[NR42] & 0xF8 == 0 {
+            None
+        } else if self.noise.enabled && self.noise.lfsr & 0x01 == 0 {
+            Some(self.noise.envelope.volume)
+        } else {
+            Some(0)
+        };
+
+        let digital = [
+            square(&self.square1, self.registers[NR11], self.registers[NR12]),
+            square(&self.square2, self.registers[NR21], self.registers[NR22]),
+            wave,
+            noise,
+        ];
+
+        let mut outputs = [0.0; 4];
+        for (output, digital) in outputs.iter_mut().zip(digital.iter()) {
+            if let Some(digital) = *digital {
+                *output = digital as f32 / 7.5 - 1.0;
+            }
+        }
+        outputs
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        // Everything happens on M-cycle boundaries, which is enough for the
+        // channels and keeps the sample averaging cheap.
+        for _ in 0..cycles / 4 {
+            if self.powered() {
+                self.sequencer_cycles += 4;
+                if self.sequencer_cycles >= FRAME_SEQUENCER_CYCLES {
+                    self.sequencer_cycles -= FRAME_SEQUENCER_CYCLES;
+                    self.clock_sequencer();
+                }
+                self.run_channels(4);
+            }
+
+            let outputs = self.channel_outputs();
+            let mixed = mix(&self.registers, &outputs);
+            self.output.add(mixed);
+
+            if let Some(ref mut recording) = self.recording {
+                recording.mix.add(mixed);
+                for (channel, stem) in recording.stems.iter_mut().enumerate() {
+                    let mut alone = [0.0; 4];
+                    alone[channel] = outputs[channel];
+                    stem.add(mix(&self.registers, &alone));
+                }
+            }
+        }
+    }
+
+    /// Changes the rate of the generated samples, in Hz. Fractional rates
+    /// let the frontend nudge it to keep up with the host.
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.output.cycles_per_sample = CYCLES_PER_SECOND as f64 / sample_rate;
+    }
+
+    /// Takes the samples generated so far, interleaved as left and right.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        mem::take(&mut self.output.samples)
+    }
+
+    /// Starts recording the output, replacing any previous recording.
+    pub fn record(&mut self, recording: Recording) {
+        self.recording = Some(recording);
+    }
+
+    pub fn recording_mut(&mut self) -> Option<&mut Recording> {
+        self.recording.as_mut()
+    }
+
+    pub fn new(sample_rate: u32) -> Apu {
+        let mut registers = [0; 0x17];
+        // As the DMG boot ROM leaves them.
+        registers[NR11] = 0x80;
+        registers[NR12] = 0xF3;
+        registers[NR50] = 0x77;
+        registers[NR51] = 0xF3;
+        registers[NR52] = 0x80;
+
+        Apu {
+            registers,
+            wave_ram: [0; 0x10],
+            square1: Square::default(),
+            square2: Square::default(),
+            wave: Wave::default(),
+            noise: Noise::default(),
+            sweep: Sweep::default(),
+            sequencer_step: 0,
+            sequencer_cycles: 0,
+            output: Output::new(sample_rate as f64, true),
+            recording: None,
+        }
+    }
+}
+
+fn trigger_square(square: &mut Square, nrx2: u8, period: u32) {
+    square.enabled = nrx2 & 0xF8 != 0;
+    if square.length == 0 {
+        square.length = 64;
+    }
+    square.timer = period;
+    square.envelope.trigger(nrx2);
+}
+
+/// Counts the length down while NRx4 enables it, the channel stops once
+/// it runs out.
+fn clock_length(length: &mut u16, enabled: &mut bool, nrx4: u8) {
+    if nrx4 & 0x40 == 0 || *length == 0 {
+        return;
+    }
+    *length -= 1;
+    if *length == 0 {
+        *enabled = false;
+    }
+}
+
+/// Pans the channels with NR51 and scales both sides with NR50.
+fn mix(registers: &[u8; 0x17], outputs: &[f32; 4]) -> (f32, f32) {
+    let nr50 = registers[NR50];
+    let nr51 = registers[NR51];
+
+    let mut left = 0.0;
+    let mut right = 0.0;
+    for (channel, output) in outputs.iter().enumerate() {
+        if nr51 & 0x10 << channel != 0 {
+            left += output;
+        }
+        if nr51 & 0x01 << channel != 0 {
+            right += output;
+        }
+    }
+
+    let left_volume = (nr50 >> 4 & 0x07) as f32 + 1.0;
+    let right_volume = (nr50 & 0x07) as f32 + 1.0;
+    (left * left_volume / 32.0, right * right_volume / 32.0)
+}
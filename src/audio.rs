@@ -0,0 +1,215 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+use apu::Recording;
+use hound;
+
+/// Where the samples generated by the APU end up.
+pub trait Sink {
+    /// Takes interleaved stereo samples, from -1.0 to 1.0.
+    fn queue(&mut self, samples: &[f32]);
+
+    /// Rate the APU should generate samples at, which may drift from the
+    /// nominal one to keep the sink fed.
+    fn sample_rate(&self) -> f64;
+
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Drops every sample, for machines without a sound card.
+pub struct Null {
+    sample_rate: u32,
+}
+
+impl Null {
+    pub fn new(sample_rate: u32) -> Null {
+        Null { sample_rate }
+    }
+}
+
+impl Sink for Null {
+    fn queue(&mut self, _samples: &[f32]) {}
+
+    fn sample_rate(&self) -> f64 {
+        self.sample_rate as f64
+    }
+}
+
+/// Writes the samples to a 16 bits stereo WAV file instead of playing
+/// them.
+pub struct Wav {
+    sample_rate: u32,
+    track: Option<Track>,
+}
+
+impl Wav {
+    pub fn create(path: &Path, sample_rate: u32) -> io::Result<Wav> {
+        Ok(Wav {
+            sample_rate,
+            track: Some(Track::wav(path, sample_rate)?),
+        })
+    }
+}
+
+impl Sink for Wav {
+    fn queue(&mut self, samples: &[f32]) {
+        let result = match self.track {
+            Some(ref mut track) => track.write(samples),
+            None => return,
+        };
+        if let Err(err) = result {
+            eprintln!("Could not write the audio: {}", err);
+            self.track = None;
+        }
+    }
+
+    fn sample_rate(&self) -> f64 {
+        self.sample_rate as f64
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        match self.track.take() {
+            Some(track) => track.finish(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// File of 16 bits stereo samples.
+enum Track {
+    Wav(hound::WavWriter<io::BufWriter<File>>),
+    /// Raw little endian PCM, without any header.
+    Pcm(io::BufWriter<File>),
+}
+
+impl Track {
+    fn wav(path: &Path, sample_rate: u32) -> io::Result<Track> {
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let writer = hound::WavWriter::create(path, spec).map_err(wav_error)?;
+        Ok(Track::Wav(writer))
+    }
+
+    fn pcm(path: &Path) -> io::Result<Track> {
+        Ok(Track::Pcm(io::BufWriter::new(File::create(path)?)))
+    }
+
+    fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        for sample in samples {
+            let sample = to_i16(*sample);
+            match *self {
+                Track::Wav(ref mut writer) => {
+                    writer.write_sample(sample).map_err(wav_error)?
+                }
+                Track::Pcm(ref mut writer) => {
+                    writer.write_all(&sample.to_le_bytes())?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Fills in the sizes in the WAV header, the file is broken without it.
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Track::Wav(writer) => writer.finalize().map_err(wav_error),
+            Track::Pcm(mut writer) => writer.flush(),
+        }
+    }
+}
+
+/// Names of the stems, put between the name and the extension of the
+/// recording.
+const STEMS: [&str; 4] = ["square1", "square2", "wave", "noise"];
+
+/// Writes an APU `Recording` to disk, as WAV or, for paths ending in .raw
+/// or .pcm, raw PCM. Stems go beside the mix, `out.wav` getting
+/// `out.square1.wav` up to `out.noise.wav`.
+///
+/// The recording runs at its own fixed rate, so the files come out the same
+/// on every run whatever the host plays.
+pub struct Recorder {
+    sample_rate: u32,
+    mix: Track,
+    stems: Vec<Track>,
+}
+
+impl Recorder {
+    pub fn create(
+        path: &Path,
+        sample_rate: u32,
+        stems: bool,
+    ) -> io::Result<Recorder> {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or("wav")
+            .to_string();
+        let raw = extension == "raw" || extension == "pcm";
+        let track = |path: &Path| {
+            if raw {
+                Track::pcm(path)
+            } else {
+                Track::wav(path, sample_rate)
+            }
+        };
+
+        let mut tracks = Vec::new();
+        if stems {
+            for name in &STEMS {
+                let stem = format!("{}.{}", name, extension);
+                tracks.push(track(&path.with_extension(stem))?);
+            }
+        }
+
+        Ok(Recorder {
+            sample_rate,
+            mix: track(path)?,
+            stems: tracks,
+        })
+    }
+
+    /// A recording to hand to `Apu::record`, matching the files.
+    pub fn recording(&self) -> Recording {
+        Recording::new(self.sample_rate, !self.stems.is_empty())
+    }
+
+    /// Writes what was recorded since the last call.
+    pub fn write(&mut self, recording: &mut Recording) -> io::Result<()> {
+        self.mix.write(&recording.take_mix())?;
+        for (track, samples) in
+            self.stems.iter_mut().zip(recording.take_stems())
+        {
+            track.write(&samples)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        self.mix.finish()?;
+        for track in self.stems {
+            track.finish()?;
+        }
+        Ok(())
+    }
+}
+
+/// Converts a sample to the 16 bits the files and most devices take.
+pub fn to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+}
+
+fn wav_error(err: hound::Error) -> io::Error {
+    match err {
+        hound::Error::IoError(err) => err,
+        err => io::Error::other(err.to_string()),
+    }
+}
@@ -0,0 +1,375 @@
+use std::fmt;
+
+use mbc::{Mbc, Mbc1, Mbc2, Mbc3, Mbc5, RomOnly};
+use rtc::{Clock, Rtc};
+
+const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83,
+    0x00, 0x0C, 0x00, 0x0D, 0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E,
+    0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99, 0xBB, 0xBB, 0x67, 0x63,
+    0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Controller {
+    None,
+    Mbc1,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+    Unsupported,
+}
+
+/// Decoded cartridge type byte (0x0147).
+#[derive(Clone, Copy)]
+pub struct CartridgeType {
+    pub code: u8,
+    pub controller: Controller,
+    pub ram: bool,
+    pub battery: bool,
+    pub timer: bool,
+    pub rumble: bool,
+}
+
+impl CartridgeType {
+    fn from_code(code: u8) -> CartridgeType {
+        let (controller, ram, battery, timer, rumble) = match code {
+            0x00 => (Controller::None, false, false, false, false),
+            0x01 => (Controller::Mbc1, false, false, false, false),
+            0x02 => (Controller::Mbc1, true, false, false, false),
+            0x03 => (Controller::Mbc1, true, true, false, false),
+            0x05 => (Controller::Mbc2, true, false, false, false),
+            0x06 => (Controller::Mbc2, true, true, false, false),
+            0x08 => (Controller::None, true, false, false, false),
+            0x09 => (Controller::None, true, true, false, false),
+            0x0F => (Controller::Mbc3, false, true, true, false),
+            0x10 => (Controller::Mbc3, true, true, true, false),
+            0x11 => (Controller::Mbc3, false, false, false, false),
+            0x12 => (Controller::Mbc3, true, false, false, false),
+            0x13 => (Controller::Mbc3, true, true, false, false),
+            0x19 => (Controller::Mbc5, false, false, false, false),
+            0x1A => (Controller::Mbc5, true, false, false, false),
+            0x1B => (Controller::Mbc5, true, true, false, false),
+            0x1C => (Controller::Mbc5, false, false, false, true),
+            0x1D => (Controller::Mbc5, true, false, false, true),
+            0x1E => (Controller::Mbc5, true, true, false, true),
+            _ => (Controller::Unsupported, false, false, false, false),
+        };
+
+        CartridgeType {
+            code,
+            controller,
+            ram,
+            battery,
+            timer,
+            rumble,
+        }
+    }
+}
+
+impl fmt::Display for CartridgeType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let controller = match self.controller {
+            Controller::None => "ROM",
+            Controller::Mbc1 => "MBC1",
+            Controller::Mbc2 => "MBC2",
+            Controller::Mbc3 => "MBC3",
+            Controller::Mbc5 => "MBC5",
+            Controller::Unsupported => {
+                return write!(f, "Unsupported {:02X}", self.code)
+            }
+        };
+
+        write!(f, "{}", controller)?;
+        if self.timer {
+            write!(f, "+TIMER")?;
+        }
+        if self.rumble {
+            write!(f, "+RUMBLE")?;
+        }
+        if self.ram {
+            write!(f, "+RAM")?;
+        }
+        if self.battery {
+            write!(f, "+BATTERY")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(PartialEq)]
+pub enum CgbSupport {
+    None,
+    Compatible,
+    Exclusive,
+}
+
+pub enum Destination {
+    Japanese,
+    Overseas,
+}
+
+/// Cartridge header found at 0x0100-0x014F.
+pub struct Header {
+    pub title: String,
+    pub manufacturer_code: String,
+    pub cgb: CgbSupport,
+    pub sgb: bool,
+    pub cartridge_type: CartridgeType,
+    pub rom_size: usize,
+    pub ram_size: usize,
+    pub destination: Destination,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+}
+
+fn ascii(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .take_while(|byte| **byte != 0)
+        .map(|byte| *byte as char)
+        .collect()
+}
+
+impl Header {
+    pub fn parse(rom: &[u8]) -> Result<Header, &'static str> {
+        if rom.len() < 0x150 {
+            return Err("ROM is too small to hold a header");
+        }
+
+        let cgb = match rom[0x143] {
+            0x80 => CgbSupport::Compatible,
+            0xC0 => CgbSupport::Exclusive,
+            _ => CgbSupport::None,
+        };
+
+        // Newer cartridges shortened the title to fit the manufacturer code
+        // and the CGB flag.
+        let (title, manufacturer_code) = if cgb == CgbSupport::None {
+            (ascii(&rom[0x134..0x144]), String::new())
+        } else {
+            (ascii(&rom[0x134..0x13F]), ascii(&rom[0x13F..0x143]))
+        };
+
+        let rom_size = match rom[0x148] {
+            size @ 0x00..=0x08 => 0x8000 << size,
+            0x52 => 72 * 0x4000,
+            0x53 => 80 * 0x4000,
+            0x54 => 96 * 0x4000,
+            _ => return Err("Unknown ROM size"),
+        };
+
+        let ram_size = match rom[0x149] {
+            0x00 => 0,
+            0x01 => 0x800,
+            0x02 => 0x2000,
+            0x03 => 0x8000,
+            0x04 => 0x20000,
+            0x05 => 0x10000,
+            _ => return Err("Unknown RAM size"),
+        };
+
+        let destination = match rom[0x14A] {
+            0x00 => Destination::Japanese,
+            _ => Destination::Overseas,
+        };
+
+        Ok(Header {
+            title,
+            manufacturer_code,
+            cgb,
+            sgb: rom[0x146] == 0x03,
+            cartridge_type: CartridgeType::from_code(rom[0x147]),
+            rom_size,
+            ram_size,
+            destination,
+            version: rom[0x14C],
+            header_checksum: rom[0x14D],
+            global_checksum: (rom[0x14E] as u16) << 8 | rom[0x14F] as u16,
+        })
+    }
+
+    pub fn logo_valid(rom: &[u8]) -> bool {
+        rom[0x104..0x134] == NINTENDO_LOGO[..]
+    }
+
+    /// Checksum of 0x0134-0x014C, verified by the boot ROM.
+    pub fn compute_header_checksum(rom: &[u8]) -> u8 {
+        rom[0x134..0x14D]
+            .iter()
+            .fold(0u8, |sum, byte| sum.wrapping_sub(*byte).wrapping_sub(1))
+    }
+
+    /// Sum of every byte of the ROM but the global checksum itself.
+    pub fn compute_global_checksum(rom: &[u8]) -> u16 {
+        rom.iter()
+            .enumerate()
+            .filter(|&(address, _)| address != 0x14E && address != 0x14F)
+            .fold(0u16, |sum, (_, byte)| sum.wrapping_add(*byte as u16))
+    }
+}
+
+impl fmt::Display for Header {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.title)?;
+        if !self.manufacturer_code.is_empty() {
+            write!(f, " [{}]", self.manufacturer_code)?;
+        }
+        write!(
+            f,
+            " v{} -- {}, {} KiB ROM, {} KiB RAM",
+            self.version,
+            self.cartridge_type,
+            self.rom_size / 1024,
+            self.ram_size / 1024
+        )?;
+        match self.cgb {
+            CgbSupport::None => {}
+            CgbSupport::Compatible => write!(f, ", CGB")?,
+            CgbSupport::Exclusive => write!(f, ", CGB only")?,
+        };
+        if self.sgb {
+            write!(f, ", SGB")?;
+        }
+        match self.destination {
+            Destination::Japanese => write!(f, ", Japan"),
+            Destination::Overseas => write!(f, ", Overseas"),
+        }
+    }
+}
+
+/// Problems the boot ROM does not check, so the game may still run.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Warning {
+    GlobalChecksum,
+    /// Size the header gives and the size of the ROM, in bytes.
+    RomSize(usize, usize),
+}
+
+pub struct Cartridge {
+    pub header: Header,
+    mbc: Box<dyn Mbc>,
+    /// Whether the RAM changed since the last save.
+    dirty: bool,
+    warnings: Vec<Warning>,
+}
+
+impl Cartridge {
+    /// Parses and validates the ROM, refusing dumps the boot ROM would
+    /// refuse to run and warning about the ones it would accept.
+    pub fn new(rom: Vec<u8>, clock: Clock) -> Result<Cartridge, &'static str> {
+        let header = Header::parse(&rom)?;
+
+        if !Header::logo_valid(&rom) {
+            return Err("Nintendo logo does not match");
+        }
+
+        if Header::compute_header_checksum(&rom) != header.header_checksum {
+            return Err("Header checksum does not match");
+        }
+
+        let mut warnings = Vec::new();
+        if Header::compute_global_checksum(&rom) != header.global_checksum {
+            warnings.push(Warning::GlobalChecksum);
+        }
+
+        if rom.len() != header.rom_size {
+            warnings.push(Warning::RomSize(header.rom_size, rom.len()));
+        }
+
+        let cartridge_type = header.cartridge_type;
+        let mbc: Box<dyn Mbc> = match cartridge_type.controller {
+            Controller::None => Box::new(RomOnly::new(rom, header.ram_size)),
+            Controller::Mbc1 => Box::new(Mbc1::new(rom, header.ram_size)),
+            Controller::Mbc2 => Box::new(Mbc2::new(rom)),
+            Controller::Mbc3 => {
+                let rtc = if cartridge_type.timer {
+                    Some(Rtc::new(clock))
+                } else {
+                    None
+                };
+                Box::new(Mbc3::new(rom, header.ram_size, rtc))
+            }
+            Controller::Mbc5 => {
+                Box::new(Mbc5::new(rom, header.ram_size, cartridge_type.rumble))
+            }
+            Controller::Unsupported => {
+                return Err("Unsupported cartridge type");
+            }
+        };
+
+        Ok(Cartridge {
+            header,
+            mbc,
+            dirty: false,
+            warnings,
+        })
+    }
+
+    /// What was wrong with the ROM but did not stop it from loading.
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    pub fn read_rom(&self, address: u16) -> u8 {
+        self.mbc.read_rom(address)
+    }
+
+    pub fn write_rom(&mut self, address: u16, value: u8) {
+        self.mbc.write_rom(address, value);
+    }
+
+    pub fn read_ram(&self, address: u16) -> u8 {
+        self.mbc.read_ram(address)
+    }
+
+    pub fn write_ram(&mut self, address: u16, value: u8) {
+        self.mbc.write_ram(address, value);
+        self.dirty = true;
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        self.mbc.tick(cycles);
+    }
+
+    /// Only cartridges with a battery keep their RAM once powered off.
+    pub fn has_battery(&self) -> bool {
+        self.header.cartridge_type.battery
+    }
+
+    /// Whether `save` has anything new. The clock keeps running without any
+    /// write to the RAM, so cartridges with one always do.
+    pub fn dirty(&self) -> bool {
+        self.dirty || self.header.cartridge_type.timer
+    }
+
+    /// Contents of a raw `.sav` file: the external RAM, followed by the RTC
+    /// footer on MBC3 cartridges with a timer.
+    pub fn save(&mut self) -> Vec<u8> {
+        let mut bytes = self.mbc.ram().to_vec();
+        if let Some(rtc) = self.mbc.rtc() {
+            bytes.extend(rtc.save());
+        }
+        self.dirty = false;
+        bytes
+    }
+
+    /// Restores a `.sav` file written by `save`. Files without the RTC
+    /// footer, or smaller than the RAM, are accepted as other emulators
+    /// write them too.
+    pub fn load(&mut self, bytes: &[u8]) -> Result<(), &'static str> {
+        let ram = self.mbc.ram_mut();
+        let size = ram.len().min(bytes.len());
+        ram[..size].copy_from_slice(&bytes[..size]);
+
+        let footer = &bytes[size..];
+        if let Some(rtc) = self.mbc.rtc() {
+            if !footer.is_empty() {
+                rtc.load(footer)?;
+            }
+        }
+        self.dirty = false;
+        Ok(())
+    }
+}
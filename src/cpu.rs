@@ -2,44 +2,76 @@ use std::fmt;
 
 use memory;
 
+/// T-cycles in one second of emulated time.
+pub const CYCLES_PER_SECOND: u32 = 4_194_304;
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct CPU {
     memory: memory::Memory,
     pc: u16,
-    a: i8,
-    b: i8,
-    c: i8,
-    d: i8,
-    e: i8,
-    h: i8,
-    l: i8,
-    fz: bool,
-    fc: bool,
+    sp: u16,
+    a: u8,
+    b: u8,
+    c: u8,
+    d: u8,
+    e: u8,
+    h: u8,
+    l: u8,
+    f: u8,
+    ime: bool,
+    ime_scheduled: bool,
+    halt_bug: bool,
+    state: State,
+    cycles: u64,
+    /// Prints every instruction as it runs.
+    trace: bool,
+}
+
+#[derive(PartialEq)]
+enum State {
+    Running,
+    Halted,
+    Stopped,
+    Locked,
 }
 
 enum Flags {
     Z,
+    N,
+    H,
     C,
     Always,
 }
 
 impl Flags {
+    /// Bit of the flag inside the F register.
+    fn mask(&self) -> u8 {
+        match self {
+            Flags::Z => 0x80,
+            Flags::N => 0x40,
+            Flags::H => 0x20,
+            Flags::C => 0x10,
+            Flags::Always => 0x00,
+        }
+    }
+
     fn get(&self, cpu: &CPU) -> bool {
         match self {
-            Flags::Z => cpu.fz,
-            Flags::C => cpu.fc,
             Flags::Always => true,
+            _ => cpu.f & self.mask() != 0,
         }
     }
 
     fn set(&self, cpu: &mut CPU, value: bool) {
         match self {
-            Flags::Z => {
-                cpu.fz = value;
-            }
-            Flags::C => {
-                cpu.fc = value;
+            Flags::Always => panic!("Unsetable!"),
+            _ => {
+                if value {
+                    cpu.f |= self.mask();
+                } else {
+                    cpu.f &= !self.mask();
+                }
             }
-            _ => panic!("Unsetable!"),
         };
     }
 }
@@ -48,6 +80,8 @@ impl fmt::Display for Flags {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let result = match self {
             Flags::Z => "Z",
+            Flags::N => "N",
+            Flags::H => "H",
             Flags::C => "C",
             _ => "",
         };
@@ -64,50 +98,71 @@ enum Register {
     E,
     H,
     L,
+    AF,
     BC,
     HL,
     DE,
+    SP,
 }
 
 impl Register {
-    fn read(&self, cpu: &CPU) -> i16 {
+    fn read(&self, cpu: &CPU) -> u16 {
         match self {
-            Register::A => cpu.a as i16,
-            Register::B => cpu.b as i16,
-            Register::C => cpu.c as i16,
-            Register::D => cpu.d as i16,
-            Register::E => cpu.e as i16,
-            Register::H => cpu.h as i16,
-            Register::L => cpu.l as i16,
-            Register::BC => ((cpu.b as u16) << 8 | (cpu.c as u8) as u16) as i16,
-            Register::HL => ((cpu.h as u16) << 8 | (cpu.l as u8) as u16) as i16,
-            Register::DE => ((cpu.d as u16) << 8 | (cpu.e as u8) as u16) as i16,
+            Register::A => cpu.a as u16,
+            Register::B => cpu.b as u16,
+            Register::C => cpu.c as u16,
+            Register::D => cpu.d as u16,
+            Register::E => cpu.e as u16,
+            Register::H => cpu.h as u16,
+            Register::L => cpu.l as u16,
+            Register::AF => (cpu.a as u16) << 8 | cpu.f as u16,
+            Register::BC => (cpu.b as u16) << 8 | cpu.c as u16,
+            Register::HL => (cpu.h as u16) << 8 | cpu.l as u16,
+            Register::DE => (cpu.d as u16) << 8 | cpu.e as u16,
+            Register::SP => cpu.sp,
         }
     }
 
-    fn write(&self, cpu: &mut CPU, value: i16) {
+    fn write(&self, cpu: &mut CPU, value: u16) {
         match self {
-            Register::A => cpu.a = value as i8,
-            Register::B => cpu.b = value as i8,
-            Register::C => cpu.c = value as i8,
-            Register::D => cpu.d = value as i8,
-            Register::E => cpu.e = value as i8,
-            Register::H => cpu.h = value as i8,
-            Register::L => cpu.l = value as i8,
+            Register::A => cpu.a = value as u8,
+            Register::B => cpu.b = value as u8,
+            Register::C => cpu.c = value as u8,
+            Register::D => cpu.d = value as u8,
+            Register::E => cpu.e = value as u8,
+            Register::H => cpu.h = value as u8,
+            Register::L => cpu.l = value as u8,
+            Register::AF => {
+                cpu.a = (value >> 8) as u8;
+                // The lower nibble of F is always zero.
+                cpu.f = value as u8 & 0xF0;
+            }
             Register::BC => {
-                cpu.b = (value >> 8) as i8;
-                cpu.c = value as i8;
+                cpu.b = (value >> 8) as u8;
+                cpu.c = value as u8;
             }
             Register::HL => {
-                cpu.h = (value >> 8) as i8;
-                cpu.l = value as i8;
+                cpu.h = (value >> 8) as u8;
+                cpu.l = value as u8;
             }
             Register::DE => {
-                cpu.d = (value >> 8) as i8;
-                cpu.e = value as i8;
+                cpu.d = (value >> 8) as u8;
+                cpu.e = value as u8;
             }
+            Register::SP => cpu.sp = value,
         };
     }
+
+    fn is_word(&self) -> bool {
+        matches!(
+            self,
+            Register::AF
+                | Register::BC
+                | Register::HL
+                | Register::DE
+                | Register::SP
+        )
+    }
 }
 
 impl fmt::Display for Register {
@@ -120,9 +175,11 @@ impl fmt::Display for Register {
             Register::E => "E",
             Register::H => "H",
             Register::L => "L",
+            Register::AF => "AF",
             Register::BC => "BC",
             Register::HL => "HL",
             Register::DE => "DE",
+            Register::SP => "SP",
         };
 
         write!(f, "{}", result)
@@ -132,59 +189,109 @@ impl fmt::Display for Register {
 enum Operand {
     AddressU8(u16),
     AddressU16(u16),
-    Move(u16), // u16 is the address of number to move by
+    PointerU8(u16),     // u16 is the address of the pointer
+    PointerU16(u16),    // u16 is the address of the pointer
+    HighAddressU8(u16), // u16 is the address of the offset from 0xFF00
+    Move(u16),          // u16 is the address of number to move by
     Flag(Flags),
     NotFlag(Flags),
     Register(Register),
     RegisterAddressU8(Register), // Decay to AddressU8
-    RegisterAddressU16(Register), // Decay to AddressU16
+    RegisterHighAddressU8(Register), // Decay to AddressU8 from 0xFF00
 }
 
 impl Operand {
-    fn get(&self, cpu: &CPU) -> i16 {
+    /// Operand encoded in the three lower bits of most opcodes.
+    fn from_index(index: u8) -> Operand {
+        match index & 0x07 {
+            0 => Operand::Register(Register::B),
+            1 => Operand::Register(Register::C),
+            2 => Operand::Register(Register::D),
+            3 => Operand::Register(Register::E),
+            4 => Operand::Register(Register::H),
+            5 => Operand::Register(Register::L),
+            6 => Operand::RegisterAddressU8(Register::HL),
+            _ => Operand::Register(Register::A),
+        }
+    }
+
+    fn is_conditional(&self) -> bool {
+        !matches!(self, Operand::Flag(Flags::Always))
+    }
+
+    fn is_word(&self) -> bool {
         match self {
-            Operand::AddressU8(address) => {
-                cpu.memory.get_byte(*address).unwrap() as i16
+            Operand::AddressU16(_) | Operand::PointerU16(_) => true,
+            Operand::Register(register) => register.is_word(),
+            _ => false,
+        }
+    }
+
+    fn get(&self, cpu: &CPU) -> u16 {
+        match self {
+            Operand::AddressU8(address) => cpu.memory.get_byte(*address) as u16,
+            Operand::AddressU16(address) => cpu.memory.get_word(*address),
+            Operand::PointerU8(address) => {
+                let pointer = cpu.memory.get_word(*address);
+                Operand::AddressU8(pointer).get(cpu)
             }
-            Operand::AddressU16(address) => {
-                cpu.memory.get_word(*address).unwrap()
+            Operand::PointerU16(address) => {
+                let pointer = cpu.memory.get_word(*address);
+                Operand::AddressU16(pointer).get(cpu)
+            }
+            Operand::HighAddressU8(address) => {
+                let offset = cpu.memory.get_byte(*address);
+                Operand::AddressU8(0xFF00 | offset as u16).get(cpu)
             }
             Operand::Register(register) => register.read(cpu),
             Operand::RegisterAddressU8(register) => {
                 let result = register.read(cpu);
-                Operand::AddressU8(result as u16).get(cpu)
+                Operand::AddressU8(result).get(cpu)
             }
-            Operand::RegisterAddressU16(register) => {
-                let result = register.read(cpu);
-                Operand::AddressU16(result as u16).get(cpu)
+            Operand::RegisterHighAddressU8(register) => {
+                let offset = register.read(cpu);
+                Operand::AddressU8(0xFF00 | offset).get(cpu)
             }
-            Operand::Flag(flag) => flag.get(cpu) as i16,
-            Operand::NotFlag(flag) => !flag.get(cpu) as i16,
+            Operand::Flag(flag) => flag.get(cpu) as u16,
+            Operand::NotFlag(flag) => !flag.get(cpu) as u16,
             Operand::Move(address) => {
-                let by = cpu.memory.get_byte(*address).unwrap();
-                ((cpu.pc as i32) + by as i32) as i16
+                // The only signed operand: relative jumps go both ways.
+                let by = cpu.memory.get_byte(*address) as i8;
+                cpu.pc.wrapping_add(by as u16)
             }
         }
     }
 
-    fn set(&self, cpu: &mut CPU, value: i16) {
+    fn set(&self, cpu: &mut CPU, value: u16) {
         match self {
             Operand::AddressU8(address) => {
-                cpu.memory.set_byte(*address, value as i8);
+                cpu.memory.set_byte(*address, value as u8);
             }
             Operand::AddressU16(address) => {
                 cpu.memory.set_word(*address, value);
             }
+            Operand::PointerU8(address) => {
+                let pointer = cpu.memory.get_word(*address);
+                Operand::AddressU8(pointer).set(cpu, value);
+            }
+            Operand::PointerU16(address) => {
+                let pointer = cpu.memory.get_word(*address);
+                Operand::AddressU16(pointer).set(cpu, value);
+            }
+            Operand::HighAddressU8(address) => {
+                let offset = cpu.memory.get_byte(*address);
+                Operand::AddressU8(0xFF00 | offset as u16).set(cpu, value);
+            }
             Operand::Register(register) => {
                 register.write(cpu, value);
             }
             Operand::RegisterAddressU8(register) => {
-                let address = register.read(cpu) as u16;
+                let address = register.read(cpu);
                 Operand::AddressU8(address).set(cpu, value);
             }
-            Operand::RegisterAddressU16(register) => {
-                let address = register.read(cpu);
-                Operand::AddressU16(address as u16).set(cpu, value);
+            Operand::RegisterHighAddressU8(register) => {
+                let offset = register.read(cpu);
+                Operand::AddressU8(0xFF00 | offset).set(cpu, value);
             }
             Operand::Flag(flag) => flag.set(cpu, value != 0),
             Operand::NotFlag(flag) => flag.set(cpu, value != 0),
@@ -198,10 +305,15 @@ impl fmt::Display for Operand {
         match self {
             Operand::AddressU8(address) => write!(f, "({:04X})", address),
             Operand::AddressU16(address) => write!(f, "({:04X})", address),
+            Operand::PointerU8(address) => write!(f, "(({:04X}))", address),
+            Operand::PointerU16(address) => write!(f, "(({:04X}))", address),
+            Operand::HighAddressU8(address) => {
+                write!(f, "(FF00+({:04X}))", address)
+            }
             Operand::Register(register) => register.fmt(f),
             Operand::RegisterAddressU8(register) => write!(f, "({})", register),
-            Operand::RegisterAddressU16(register) => {
-                write!(f, "({})", register)
+            Operand::RegisterHighAddressU8(register) => {
+                write!(f, "(FF00+{})", register)
             }
             Operand::Flag(flag) => flag.fmt(f),
             Operand::NotFlag(flag) => write!(f, "!{}", flag),
@@ -210,76 +322,435 @@ impl fmt::Display for Operand {
     }
 }
 
+/// T-cycles of each base opcode, not counting the extra cycles of a taken
+/// conditional branch.
+#[rustfmt::skip]
+const CYCLES: [u8; 256] = [
+//  x0  x1  x2  x3  x4  x5  x6  x7  x8  x9  xA  xB  xC  xD  xE  xF
+     4, 12,  8,  8,  4,  4,  8,  4, 20,  8,  8,  8,  4,  4,  8,  4, // 0x
+     4, 12,  8,  8,  4,  4,  8,  4, 12,  8,  8,  8,  4,  4,  8,  4, // 1x
+     8, 12,  8,  8,  4,  4,  8,  4,  8,  8,  8,  8,  4,  4,  8,  4, // 2x
+     8, 12,  8,  8, 12, 12, 12,  4,  8,  8,  8,  8,  4,  4,  8,  4, // 3x
+     4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 4x
+     4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 5x
+     4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 6x
+     8,  8,  8,  8,  8,  8,  4,  8,  4,  4,  4,  4,  4,  4,  8,  4, // 7x
+     4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 8x
+     4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 9x
+     4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // Ax
+     4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // Bx
+     8, 12, 12, 16, 12, 16,  8, 16,  8, 16, 12,  4, 12, 24,  8, 16, // Cx
+     8, 12, 12,  4, 12, 16,  8, 16,  8, 16, 12,  4, 12,  4,  8, 16, // Dx
+    12, 12,  8,  4,  4, 16,  8, 16, 16,  4, 16,  4,  4,  4,  8, 16, // Ex
+    12, 12,  8,  4,  4, 16,  8, 16, 12,  8, 16,  4,  4,  4,  8, 16, // Fx
+];
+
+/// T-cycles of a CB-prefixed opcode, including the prefix itself.
+fn cb_cycles(opcode: u8) -> u8 {
+    match (opcode, opcode & 0x07) {
+        (0x40..=0x7F, 6) => 12,
+        (_, 6) => 16,
+        _ => 8,
+    }
+}
+
 enum Instructions {
     Undefined { opcode: u8 },
+    Lock { opcode: u8 },
     Nop,
     Add { op1: Operand, op2: Operand },
+    Adc { op1: Operand, op2: Operand },
+    Sub { op1: Operand, op2: Operand },
+    Sbc { op1: Operand, op2: Operand },
+    And { op1: Operand, op2: Operand },
     Xor { op1: Operand, op2: Operand },
+    Or { op1: Operand, op2: Operand },
+    Cp { op1: Operand, op2: Operand },
+    AddSp { op: Operand, by: Operand },
     Load { op1: Operand, op2: Operand },
     Jp { cod: Operand, op: Operand },
+    Call { cod: Operand, op: Operand },
+    Ret { cod: Operand },
+    Reti,
+    Rst { address: u16 },
+    Push { op: Operand },
+    Pop { op: Operand },
     Inc { op: Operand },
     Dec { op: Operand },
+    Rlca,
+    Rla,
+    Rrca,
+    Rra,
+    Rlc { op: Operand },
+    Rl { op: Operand },
+    Rrc { op: Operand },
+    Rr { op: Operand },
     Sla { op: Operand },
+    Sra { op: Operand },
+    Swap { op: Operand },
+    Srl { op: Operand },
+    Bit { bit: u8, op: Operand },
+    Res { bit: u8, op: Operand },
+    Set { bit: u8, op: Operand },
     Stacked { stack: Vec<Instructions> },
+    Daa,
+    Scf,
+    Ccf,
+    Halt,
+    Stop,
     Di,
+    Ei,
     Cpl,
 }
 
 impl Instructions {
     fn execute(&self, cpu: &mut CPU) {
-        let result: i16;
+        let result: u16;
         match self {
             Instructions::Undefined { opcode } => panic!(
                 "{:02X}: Not identified on Address 0x{:04X}",
                 opcode, cpu.pc
             ),
+            Instructions::Lock { .. } => cpu.state = State::Locked,
             Instructions::Nop => {}
             Instructions::Add { op1, op2 } => {
-                result = op2.get(cpu);
-                let op1_value = op1.get(cpu) + result;
-                op1.set(cpu, op1_value);
+                if op1.is_word() {
+                    let op1_value = op1.get(cpu) as u32;
+                    let op2_value = op2.get(cpu) as u32;
+                    let value = op1_value + op2_value;
+                    Flags::N.set(cpu, false);
+                    Flags::H.set(
+                        cpu,
+                        (op1_value & 0xFFF) + (op2_value & 0xFFF) > 0xFFF,
+                    );
+                    Flags::C.set(cpu, value > 0xFFFF);
+                    op1.set(cpu, value as u16);
+                } else {
+                    let op1_value = op1.get(cpu);
+                    let op2_value = op2.get(cpu);
+                    let value = op1_value + op2_value;
+                    cpu.set_flags(
+                        value as u8 == 0,
+                        false,
+                        (op1_value & 0xF) + (op2_value & 0xF) > 0xF,
+                        value > 0xFF,
+                    );
+                    op1.set(cpu, value);
+                }
+            }
+            Instructions::Adc { op1, op2 } => {
+                let op1_value = op1.get(cpu);
+                let op2_value = op2.get(cpu);
+                let carry = Flags::C.get(cpu) as u16;
+                let value = op1_value + op2_value + carry;
+                cpu.set_flags(
+                    value as u8 == 0,
+                    false,
+                    (op1_value & 0xF) + (op2_value & 0xF) + carry > 0xF,
+                    value > 0xFF,
+                );
+                op1.set(cpu, value);
+            }
+            Instructions::Sub { op1, op2 } | Instructions::Cp { op1, op2 } => {
+                let op1_value = op1.get(cpu) as u8;
+                let op2_value = op2.get(cpu) as u8;
+                let value = op1_value.wrapping_sub(op2_value);
+                cpu.set_flags(
+                    value == 0,
+                    true,
+                    op2_value & 0xF > op1_value & 0xF,
+                    op2_value > op1_value,
+                );
+                if let Instructions::Sub { .. } = self {
+                    op1.set(cpu, value as u16);
+                }
+            }
+            Instructions::Sbc { op1, op2 } => {
+                let op1_value = op1.get(cpu);
+                let op2_value = op2.get(cpu);
+                let carry = Flags::C.get(cpu) as u16;
+                let value = op1_value.wrapping_sub(op2_value + carry) as u8;
+                cpu.set_flags(
+                    value == 0,
+                    true,
+                    (op2_value & 0xF) + carry > op1_value & 0xF,
+                    op2_value + carry > op1_value,
+                );
+                op1.set(cpu, value as u16);
+            }
+            Instructions::And { op1, op2 } => {
+                let op1_value = op1.get(cpu);
+                let result = op1_value & op2.get(cpu);
+                cpu.set_flags(result as u8 == 0, false, true, false);
+                op1.set(cpu, result);
             }
             Instructions::Xor { op1, op2 } => {
                 let op1_value = op1.get(cpu);
                 let result = op1_value ^ op2.get(cpu);
+                cpu.set_flags(result as u8 == 0, false, false, false);
+                op1.set(cpu, result);
+            }
+            Instructions::Or { op1, op2 } => {
+                let op1_value = op1.get(cpu);
+                let result = op1_value | op2.get(cpu);
+                cpu.set_flags(result as u8 == 0, false, false, false);
                 op1.set(cpu, result);
             }
+            Instructions::AddSp { op, by } => {
+                let by_value = by.get(cpu);
+                let sp = cpu.sp;
+                cpu.set_flags(
+                    false,
+                    false,
+                    (sp & 0xF) + (by_value & 0xF) > 0xF,
+                    (sp & 0xFF) + by_value > 0xFF,
+                );
+                let value = sp.wrapping_add(by_value as i8 as u16);
+                op.set(cpu, value);
+            }
             Instructions::Jp { cod, op } => {
                 if cod.get(cpu) > 0 {
-                    cpu.pc = op.get(cpu) as u16;
+                    cpu.pc = op.get(cpu);
+                    if cod.is_conditional() {
+                        cpu.cycles += 4;
+                    }
+                }
+            }
+            Instructions::Call { cod, op } => {
+                if cod.get(cpu) > 0 {
+                    let pc = cpu.pc;
+                    cpu.push(pc);
+                    cpu.pc = op.get(cpu);
+                    if cod.is_conditional() {
+                        cpu.cycles += 12;
+                    }
+                }
+            }
+            Instructions::Ret { cod } => {
+                if cod.get(cpu) > 0 {
+                    cpu.pc = cpu.pop();
+                    if cod.is_conditional() {
+                        cpu.cycles += 12;
+                    }
                 }
             }
+            Instructions::Reti => {
+                cpu.pc = cpu.pop();
+                cpu.ime = true;
+            }
+            Instructions::Rst { address } => {
+                let pc = cpu.pc;
+                cpu.push(pc);
+                cpu.pc = *address;
+            }
+            Instructions::Push { op } => {
+                let value = op.get(cpu);
+                cpu.push(value);
+            }
+            Instructions::Pop { op } => {
+                let value = cpu.pop();
+                op.set(cpu, value);
+            }
             Instructions::Load { op1, op2 } => {
                 result = op2.get(cpu);
                 op1.set(cpu, result);
             }
             Instructions::Inc { op } => {
-                result = op.get(cpu) + 1;
+                let value = op.get(cpu);
+                result = value.wrapping_add(1);
                 op.set(cpu, result);
+
+                if !op.is_word() {
+                    Flags::Z.set(cpu, result as u8 == 0);
+                    Flags::N.set(cpu, false);
+                    Flags::H.set(cpu, value & 0xF == 0xF);
+                }
             }
             Instructions::Dec { op } => {
-                result = op.get(cpu) - 1;
+                let value = op.get(cpu);
+                result = value.wrapping_sub(1);
                 op.set(cpu, result);
 
-                cpu.fz = result == 0;
+                if !op.is_word() {
+                    Flags::Z.set(cpu, result as u8 == 0);
+                    Flags::N.set(cpu, true);
+                    Flags::H.set(cpu, value & 0xF == 0);
+                }
+            }
+            Instructions::Rlca => {
+                let a = cpu.a;
+                cpu.a = a.rotate_left(1);
+                cpu.set_flags(false, false, false, a & 0x80 != 0);
+            }
+            Instructions::Rla => {
+                let a = cpu.a;
+                cpu.a = a << 1 | Flags::C.get(cpu) as u8;
+                cpu.set_flags(false, false, false, a & 0x80 != 0);
+            }
+            Instructions::Rrca => {
+                let a = cpu.a;
+                cpu.a = a.rotate_right(1);
+                cpu.set_flags(false, false, false, a & 0x01 != 0);
+            }
+            Instructions::Rra => {
+                let a = cpu.a;
+                cpu.a = a >> 1 | (Flags::C.get(cpu) as u8) << 7;
+                cpu.set_flags(false, false, false, a & 0x01 != 0);
+            }
+            Instructions::Rlc { op } => {
+                let value = op.get(cpu) as u8;
+                let result = value.rotate_left(1);
+                cpu.set_flags(result == 0, false, false, value & 0x80 != 0);
+                op.set(cpu, result as u16);
+            }
+            Instructions::Rl { op } => {
+                let value = op.get(cpu) as u8;
+                let result = value << 1 | Flags::C.get(cpu) as u8;
+                cpu.set_flags(result == 0, false, false, value & 0x80 != 0);
+                op.set(cpu, result as u16);
+            }
+            Instructions::Rrc { op } => {
+                let value = op.get(cpu) as u8;
+                let result = value.rotate_right(1);
+                cpu.set_flags(result == 0, false, false, value & 0x01 != 0);
+                op.set(cpu, result as u16);
+            }
+            Instructions::Rr { op } => {
+                let value = op.get(cpu) as u8;
+                let result = value >> 1 | (Flags::C.get(cpu) as u8) << 7;
+                cpu.set_flags(result == 0, false, false, value & 0x01 != 0);
+                op.set(cpu, result as u16);
             }
             Instructions::Sla { op } => {
-                result = op.get(cpu) << 1;
-                op.set(cpu, result);
+                let value = op.get(cpu) as u8;
+                let result = value << 1;
+                cpu.set_flags(result == 0, false, false, value & 0x80 != 0);
+                op.set(cpu, result as u16);
+            }
+            Instructions::Sra { op } => {
+                let value = op.get(cpu) as u8;
+                let result = value >> 1 | value & 0x80;
+                cpu.set_flags(result == 0, false, false, value & 0x01 != 0);
+                op.set(cpu, result as u16);
+            }
+            Instructions::Swap { op } => {
+                let value = op.get(cpu) as u8;
+                let result = value.rotate_left(4);
+                cpu.set_flags(result == 0, false, false, false);
+                op.set(cpu, result as u16);
+            }
+            Instructions::Srl { op } => {
+                let value = op.get(cpu) as u8;
+                let result = value >> 1;
+                cpu.set_flags(result == 0, false, false, value & 0x01 != 0);
+                op.set(cpu, result as u16);
+            }
+            Instructions::Bit { bit, op } => {
+                let value = op.get(cpu) as u8;
+                Flags::Z.set(cpu, value & (1 << bit) == 0);
+                Flags::N.set(cpu, false);
+                Flags::H.set(cpu, true);
+            }
+            Instructions::Res { bit, op } => {
+                let value = op.get(cpu) as u8;
+                op.set(cpu, (value & !(1 << bit)) as u16);
+            }
+            Instructions::Set { bit, op } => {
+                let value = op.get(cpu) as u8;
+                op.set(cpu, (value | 1 << bit) as u16);
             }
             Instructions::Stacked { stack } => {
                 for instr in stack.iter() {
                     instr.execute(cpu);
                 }
             }
+            Instructions::Daa => {
+                let mut a = cpu.a;
+                let mut carry = Flags::C.get(cpu);
+                if Flags::N.get(cpu) {
+                    if carry {
+                        a = a.wrapping_sub(0x60);
+                    }
+                    if Flags::H.get(cpu) {
+                        a = a.wrapping_sub(0x06);
+                    }
+                } else {
+                    if carry || a > 0x99 {
+                        a = a.wrapping_add(0x60);
+                        carry = true;
+                    }
+                    if Flags::H.get(cpu) || a & 0x0F > 0x09 {
+                        a = a.wrapping_add(0x06);
+                    }
+                }
+                cpu.a = a;
+                Flags::Z.set(cpu, a == 0);
+                Flags::H.set(cpu, false);
+                Flags::C.set(cpu, carry);
+            }
+            Instructions::Scf => {
+                Flags::N.set(cpu, false);
+                Flags::H.set(cpu, false);
+                Flags::C.set(cpu, true);
+            }
+            Instructions::Ccf => {
+                let carry = Flags::C.get(cpu);
+                Flags::N.set(cpu, false);
+                Flags::H.set(cpu, false);
+                Flags::C.set(cpu, !carry);
+            }
             Instructions::Cpl => {
                 result = !Operand::Register(Register::A).get(cpu);
                 Operand::Register(Register::A).set(cpu, result);
+                Flags::N.set(cpu, true);
+                Flags::H.set(cpu, true);
             }
-            Instructions::Di => {}
+            Instructions::Halt => {
+                // With IME unset and an interrupt already pending the CPU
+                // does not halt, but fails to increment PC on the next fetch.
+                if !cpu.ime && cpu.memory.pending_interrupt().is_some() {
+                    cpu.halt_bug = true;
+                } else {
+                    cpu.state = State::Halted;
+                }
+            }
+            Instructions::Stop => {
+                if cpu.memory.speed_switch_armed() {
+                    cpu.memory.switch_speed();
+                } else {
+                    // Only a press after this point wakes the CPU up.
+                    cpu.memory.take_joypad_pressed();
+                    cpu.state = State::Stopped;
+                }
+            }
+            Instructions::Di => {
+                cpu.ime = false;
+                cpu.ime_scheduled = false;
+            }
+            Instructions::Ei => cpu.ime_scheduled = true,
         };
     }
 
+    /// Decodes the opcode that follows the 0xCB prefix.
+    fn decode_cb(opcode: u8) -> (Instructions, u8) {
+        let op = Operand::from_index(opcode);
+        let bit = (opcode >> 3) & 0x07;
+        let instr = match opcode {
+            0x00..=0x07 => Instructions::Rlc { op },
+            0x08..=0x0F => Instructions::Rrc { op },
+            0x10..=0x17 => Instructions::Rl { op },
+            0x18..=0x1F => Instructions::Rr { op },
+            0x20..=0x27 => Instructions::Sla { op },
+            0x28..=0x2F => Instructions::Sra { op },
+            0x30..=0x37 => Instructions::Swap { op },
+            0x38..=0x3F => Instructions::Srl { op },
+            0x40..=0x7F => Instructions::Bit { bit, op },
+            0x80..=0xBF => Instructions::Res { bit, op },
+            _ => Instructions::Set { bit, op },
+        };
+        (instr, 2)
+    }
+
     fn decode(opcode: u8, pc: &u16) -> (Instructions, u8) {
         let mut result = 1;
         let instr = match opcode {
@@ -289,190 +760,522 @@ impl Instructions {
 
                 Instructions::Load {
                     op1: Operand::Register(Register::BC),
-                    op2: Operand::AddressU16(pc + 1),
+                    op2: Operand::AddressU16(pc.wrapping_add(1)),
                 }
             }
+            0x02 => Instructions::Load {
+                op1: Operand::RegisterAddressU8(Register::BC),
+                op2: Operand::Register(Register::A),
+            },
             0x03 => Instructions::Inc {
                 op: Operand::Register(Register::BC),
             },
-            0x05 => Instructions::Dec {
-                op: Operand::Register(Register::B),
-            },
-            0x06 => {
+            0x04 | 0x0C | 0x14 | 0x1C | 0x24 | 0x2C | 0x34 | 0x3C => {
+                Instructions::Inc {
+                    op: Operand::from_index(opcode >> 3),
+                }
+            }
+            0x05 | 0x0D | 0x15 | 0x1D | 0x25 | 0x2D | 0x35 | 0x3D => {
+                Instructions::Dec {
+                    op: Operand::from_index(opcode >> 3),
+                }
+            }
+            0x06 | 0x0E | 0x16 | 0x1E | 0x26 | 0x2E | 0x36 | 0x3E => {
                 result += 1;
 
                 Instructions::Load {
-                    op1: Operand::Register(Register::B),
-                    op2: Operand::AddressU8(pc + 1),
+                    op1: Operand::from_index(opcode >> 3),
+                    op2: Operand::AddressU8(pc.wrapping_add(1)),
+                }
+            }
+            0x07 => Instructions::Rlca,
+            0x08 => {
+                result += 2;
+
+                Instructions::Load {
+                    op1: Operand::PointerU16(pc.wrapping_add(1)),
+                    op2: Operand::Register(Register::SP),
                 }
             }
+            0x09 => Instructions::Add {
+                op1: Operand::Register(Register::HL),
+                op2: Operand::Register(Register::BC),
+            },
+            0x0A => Instructions::Load {
+                op1: Operand::Register(Register::A),
+                op2: Operand::RegisterAddressU8(Register::BC),
+            },
             0x0B => Instructions::Dec {
                 op: Operand::Register(Register::BC),
             },
-            0x0D => Instructions::Dec {
-                op: Operand::Register(Register::C),
-            },
-            0x0E => {
+            0x0F => Instructions::Rrca,
+            0x10 => {
                 result += 1;
 
-                Instructions::Load {
-                    op1: Operand::Register(Register::C),
-                    op2: Operand::AddressU8(pc + 1),
-                }
+                Instructions::Stop
             }
             0x11 => {
                 result += 2;
 
                 Instructions::Load {
                     op1: Operand::Register(Register::DE),
-                    op2: Operand::AddressU16(pc + 1),
+                    op2: Operand::AddressU16(pc.wrapping_add(1)),
                 }
             }
-            0x15 => Instructions::Dec {
-                op: Operand::Register(Register::D),
+            0x12 => Instructions::Load {
+                op1: Operand::RegisterAddressU8(Register::DE),
+                op2: Operand::Register(Register::A),
+            },
+            0x13 => Instructions::Inc {
+                op: Operand::Register(Register::DE),
             },
+            0x17 => Instructions::Rla,
+            0x18 => {
+                result += 1;
+
+                Instructions::Jp {
+                    cod: Operand::Flag(Flags::Always),
+                    op: Operand::Move(pc.wrapping_add(1)),
+                }
+            }
             0x19 => Instructions::Add {
                 op1: Operand::Register(Register::HL),
                 op2: Operand::Register(Register::DE),
             },
+            0x1A => Instructions::Load {
+                op1: Operand::Register(Register::A),
+                op2: Operand::RegisterAddressU8(Register::DE),
+            },
+            0x1B => Instructions::Dec {
+                op: Operand::Register(Register::DE),
+            },
+            0x1F => Instructions::Rra,
             0x20 => {
                 result += 1;
 
                 Instructions::Jp {
                     cod: Operand::NotFlag(Flags::Z),
-                    op: Operand::Move(pc + 1),
+                    op: Operand::Move(pc.wrapping_add(1)),
                 }
             }
-            0x1D => Instructions::Dec {
-                op: Operand::Register(Register::E),
-            },
             0x21 => {
                 result += 2;
 
                 Instructions::Load {
                     op1: Operand::Register(Register::HL),
-                    op2: Operand::AddressU16(pc + 1),
+                    op2: Operand::AddressU16(pc.wrapping_add(1)),
                 }
             }
-            0x22 => Instructions::Sla {
-                op: Operand::Register(Register::D),
-            },
-            0x25 => Instructions::Dec {
-                op: Operand::Register(Register::H),
+            0x22 => {
+                let stacked_instructions = vec![
+                    Instructions::Load {
+                        op1: Operand::RegisterAddressU8(Register::HL),
+                        op2: Operand::Register(Register::A),
+                    },
+                    Instructions::Inc {
+                        op: Operand::Register(Register::HL),
+                    },
+                ];
+
+                Instructions::Stacked {
+                    stack: stacked_instructions,
+                }
+            }
+            0x23 => Instructions::Inc {
+                op: Operand::Register(Register::HL),
             },
+            0x27 => Instructions::Daa,
+            0x28 => {
+                result += 1;
+
+                Instructions::Jp {
+                    cod: Operand::Flag(Flags::Z),
+                    op: Operand::Move(pc.wrapping_add(1)),
+                }
+            }
             0x29 => Instructions::Add {
                 op1: Operand::Register(Register::HL),
                 op2: Operand::Register(Register::HL),
             },
-            0x32 => {
-                let mut stacked_instructions = Vec::new();
+            0x2A => {
+                let stacked_instructions = vec![
+                    Instructions::Load {
+                        op1: Operand::Register(Register::A),
+                        op2: Operand::RegisterAddressU8(Register::HL),
+                    },
+                    Instructions::Inc {
+                        op: Operand::Register(Register::HL),
+                    },
+                ];
 
-                stacked_instructions.push(Instructions::Load {
-                    op1: Operand::RegisterAddressU8(Register::HL),
-                    op2: Operand::Register(Register::A),
-                });
+                Instructions::Stacked {
+                    stack: stacked_instructions,
+                }
+            }
+            0x2B => Instructions::Dec {
+                op: Operand::Register(Register::HL),
+            },
+            0x2F => Instructions::Cpl,
+            0x30 => {
+                result += 1;
 
-                stacked_instructions.push(Instructions::Dec {
-                    op: Operand::Register(Register::HL),
-                });
+                Instructions::Jp {
+                    cod: Operand::NotFlag(Flags::C),
+                    op: Operand::Move(pc.wrapping_add(1)),
+                }
+            }
+            0x31 => {
+                result += 2;
+
+                Instructions::Load {
+                    op1: Operand::Register(Register::SP),
+                    op2: Operand::AddressU16(pc.wrapping_add(1)),
+                }
+            }
+            0x32 => {
+                let stacked_instructions = vec![
+                    Instructions::Load {
+                        op1: Operand::RegisterAddressU8(Register::HL),
+                        op2: Operand::Register(Register::A),
+                    },
+                    Instructions::Dec {
+                        op: Operand::Register(Register::HL),
+                    },
+                ];
 
                 Instructions::Stacked {
                     stack: stacked_instructions,
                 }
             }
-            0x3E => {
+            0x33 => Instructions::Inc {
+                op: Operand::Register(Register::SP),
+            },
+            0x37 => Instructions::Scf,
+            0x38 => {
                 result += 1;
 
-                Instructions::Load {
-                    op1: Operand::Register(Register::A),
-                    op2: Operand::AddressU8(pc + 1),
+                Instructions::Jp {
+                    cod: Operand::Flag(Flags::C),
+                    op: Operand::Move(pc.wrapping_add(1)),
                 }
             }
-            0x51 => Instructions::Load {
-                op1: Operand::Register(Register::D),
-                op2: Operand::Register(Register::C),
+            0x39 => Instructions::Add {
+                op1: Operand::Register(Register::HL),
+                op2: Operand::Register(Register::SP),
             },
-            0x52 => Instructions::Load {
-                op1: Operand::Register(Register::D),
-                op2: Operand::Register(Register::D),
+            0x3A => {
+                let stacked_instructions = vec![
+                    Instructions::Load {
+                        op1: Operand::Register(Register::A),
+                        op2: Operand::RegisterAddressU8(Register::HL),
+                    },
+                    Instructions::Dec {
+                        op: Operand::Register(Register::HL),
+                    },
+                ];
+
+                Instructions::Stacked {
+                    stack: stacked_instructions,
+                }
+            }
+            0x3B => Instructions::Dec {
+                op: Operand::Register(Register::SP),
             },
-            0x53 => Instructions::Load {
-                op1: Operand::Register(Register::D),
-                op2: Operand::Register(Register::E),
+            0x3F => Instructions::Ccf,
+            0x76 => Instructions::Halt,
+            0x40..=0x7F => Instructions::Load {
+                op1: Operand::from_index(opcode >> 3),
+                op2: Operand::from_index(opcode),
             },
-            0x54 => Instructions::Load {
-                op1: Operand::Register(Register::D),
-                op2: Operand::Register(Register::H),
+            0x80..=0x87 => Instructions::Add {
+                op1: Operand::Register(Register::A),
+                op2: Operand::from_index(opcode),
             },
-            0x57 => Instructions::Load {
-                op1: Operand::Register(Register::D),
-                op2: Operand::Register(Register::A),
+            0x88..=0x8F => Instructions::Adc {
+                op1: Operand::Register(Register::A),
+                op2: Operand::from_index(opcode),
             },
-            0x4B => Instructions::Load {
-                op1: Operand::Register(Register::C),
-                op2: Operand::Register(Register::E),
+            0x90..=0x97 => Instructions::Sub {
+                op1: Operand::Register(Register::A),
+                op2: Operand::from_index(opcode),
             },
-            0x4A => Instructions::Load {
-                op1: Operand::Register(Register::C),
-                op2: Operand::Register(Register::D),
+            0x98..=0x9F => Instructions::Sbc {
+                op1: Operand::Register(Register::A),
+                op2: Operand::from_index(opcode),
             },
-            0x48 => Instructions::Load {
-                op1: Operand::Register(Register::C),
-                op2: Operand::Register(Register::B),
+            0xA0..=0xA7 => Instructions::And {
+                op1: Operand::Register(Register::A),
+                op2: Operand::from_index(opcode),
             },
-            0x49 => Instructions::Load {
-                op1: Operand::Register(Register::C),
-                op2: Operand::Register(Register::C),
+            0xA8..=0xAF => Instructions::Xor {
+                op1: Operand::Register(Register::A),
+                op2: Operand::from_index(opcode),
             },
-            0x50 => Instructions::Load {
-                op1: Operand::Register(Register::D),
-                op2: Operand::Register(Register::B),
+            0xB0..=0xB7 => Instructions::Or {
+                op1: Operand::Register(Register::A),
+                op2: Operand::from_index(opcode),
             },
-            0x55 => Instructions::Load {
-                op1: Operand::Register(Register::D),
-                op2: Operand::Register(Register::L),
+            0xB8..=0xBF => Instructions::Cp {
+                op1: Operand::Register(Register::A),
+                op2: Operand::from_index(opcode),
             },
-            0x6C => Instructions::Load {
-                op1: Operand::Register(Register::L),
-                op2: Operand::Register(Register::H),
+            0xC0 => Instructions::Ret {
+                cod: Operand::NotFlag(Flags::Z),
             },
-            0x58 => Instructions::Load {
-                op1: Operand::Register(Register::E),
-                op2: Operand::Register(Register::B),
+            0xC1 => Instructions::Pop {
+                op: Operand::Register(Register::BC),
             },
-            0x59 => Instructions::Load {
-                op1: Operand::Register(Register::E),
-                op2: Operand::Register(Register::C),
+            0xC2 => {
+                result += 2;
+
+                Instructions::Jp {
+                    cod: Operand::NotFlag(Flags::Z),
+                    op: Operand::AddressU16(pc.wrapping_add(1)),
+                }
+            }
+            0xC3 => {
+                result += 2;
+
+                Instructions::Jp {
+                    cod: Operand::Flag(Flags::Always),
+                    op: Operand::AddressU16(pc.wrapping_add(1)),
+                }
+            }
+            0xC4 => {
+                result += 2;
+
+                Instructions::Call {
+                    cod: Operand::NotFlag(Flags::Z),
+                    op: Operand::AddressU16(pc.wrapping_add(1)),
+                }
+            }
+            0xC5 => Instructions::Push {
+                op: Operand::Register(Register::BC),
             },
-            0x56 => Instructions::Load {
-                op1: Operand::Register(Register::D),
-                op2: Operand::RegisterAddressU8(Register::HL),
+            0xC6 => {
+                result += 1;
+
+                Instructions::Add {
+                    op1: Operand::Register(Register::A),
+                    op2: Operand::AddressU8(pc.wrapping_add(1)),
+                }
+            }
+            0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => {
+                Instructions::Rst {
+                    address: (opcode & 0x38) as u16,
+                }
+            }
+            0xC8 => Instructions::Ret {
+                cod: Operand::Flag(Flags::Z),
             },
-            0x66 => Instructions::Load {
-                op1: Operand::Register(Register::H),
-                op2: Operand::RegisterAddressU8(Register::HL),
+            0xC9 => Instructions::Ret {
+                cod: Operand::Flag(Flags::Always),
             },
-            0x6E => Instructions::Load {
-                op1: Operand::Register(Register::L),
-                op2: Operand::RegisterAddressU8(Register::HL),
+            0xCA => {
+                result += 2;
+
+                Instructions::Jp {
+                    cod: Operand::Flag(Flags::Z),
+                    op: Operand::AddressU16(pc.wrapping_add(1)),
+                }
+            }
+            0xCC => {
+                result += 2;
+
+                Instructions::Call {
+                    cod: Operand::Flag(Flags::Z),
+                    op: Operand::AddressU16(pc.wrapping_add(1)),
+                }
+            }
+            0xCD => {
+                result += 2;
+
+                Instructions::Call {
+                    cod: Operand::Flag(Flags::Always),
+                    op: Operand::AddressU16(pc.wrapping_add(1)),
+                }
+            }
+            0xCE => {
+                result += 1;
+
+                Instructions::Adc {
+                    op1: Operand::Register(Register::A),
+                    op2: Operand::AddressU8(pc.wrapping_add(1)),
+                }
+            }
+            0xD0 => Instructions::Ret {
+                cod: Operand::NotFlag(Flags::C),
             },
-            0x2C => Instructions::Inc {
-                op: Operand::Register(Register::L),
+            0xD1 => Instructions::Pop {
+                op: Operand::Register(Register::DE),
             },
-            0x2F => Instructions::Cpl,
-            0xAF => Instructions::Xor {
-                op1: Operand::Register(Register::A),
-                op2: Operand::Register(Register::A),
+            0xD2 => {
+                result += 2;
+
+                Instructions::Jp {
+                    cod: Operand::NotFlag(Flags::C),
+                    op: Operand::AddressU16(pc.wrapping_add(1)),
+                }
+            }
+            0xD4 => {
+                result += 2;
+
+                Instructions::Call {
+                    cod: Operand::NotFlag(Flags::C),
+                    op: Operand::AddressU16(pc.wrapping_add(1)),
+                }
+            }
+            0xD5 => Instructions::Push {
+                op: Operand::Register(Register::DE),
             },
-            0xC3 => {
+            0xD6 => {
+                result += 1;
+
+                Instructions::Sub {
+                    op1: Operand::Register(Register::A),
+                    op2: Operand::AddressU8(pc.wrapping_add(1)),
+                }
+            }
+            0xD8 => Instructions::Ret {
+                cod: Operand::Flag(Flags::C),
+            },
+            0xD9 => Instructions::Reti,
+            0xDA => {
                 result += 2;
 
                 Instructions::Jp {
-                    cod: Operand::Flag(Flags::Always),
-                    op: Operand::AddressU16(pc + 1),
+                    cod: Operand::Flag(Flags::C),
+                    op: Operand::AddressU16(pc.wrapping_add(1)),
+                }
+            }
+            0xDC => {
+                result += 2;
+
+                Instructions::Call {
+                    cod: Operand::Flag(Flags::C),
+                    op: Operand::AddressU16(pc.wrapping_add(1)),
+                }
+            }
+            0xDE => {
+                result += 1;
+
+                Instructions::Sbc {
+                    op1: Operand::Register(Register::A),
+                    op2: Operand::AddressU8(pc.wrapping_add(1)),
+                }
+            }
+            0xE0 => {
+                result += 1;
+
+                Instructions::Load {
+                    op1: Operand::HighAddressU8(pc.wrapping_add(1)),
+                    op2: Operand::Register(Register::A),
+                }
+            }
+            0xE1 => Instructions::Pop {
+                op: Operand::Register(Register::HL),
+            },
+            0xE2 => Instructions::Load {
+                op1: Operand::RegisterHighAddressU8(Register::C),
+                op2: Operand::Register(Register::A),
+            },
+            0xE5 => Instructions::Push {
+                op: Operand::Register(Register::HL),
+            },
+            0xE6 => {
+                result += 1;
+
+                Instructions::And {
+                    op1: Operand::Register(Register::A),
+                    op2: Operand::AddressU8(pc.wrapping_add(1)),
+                }
+            }
+            0xE8 => {
+                result += 1;
+
+                Instructions::AddSp {
+                    op: Operand::Register(Register::SP),
+                    by: Operand::AddressU8(pc.wrapping_add(1)),
+                }
+            }
+            0xE9 => Instructions::Jp {
+                cod: Operand::Flag(Flags::Always),
+                op: Operand::Register(Register::HL),
+            },
+            0xEA => {
+                result += 2;
+
+                Instructions::Load {
+                    op1: Operand::PointerU8(pc.wrapping_add(1)),
+                    op2: Operand::Register(Register::A),
+                }
+            }
+            0xEE => {
+                result += 1;
+
+                Instructions::Xor {
+                    op1: Operand::Register(Register::A),
+                    op2: Operand::AddressU8(pc.wrapping_add(1)),
                 }
             }
+            0xF0 => {
+                result += 1;
+
+                Instructions::Load {
+                    op1: Operand::Register(Register::A),
+                    op2: Operand::HighAddressU8(pc.wrapping_add(1)),
+                }
+            }
+            0xF1 => Instructions::Pop {
+                op: Operand::Register(Register::AF),
+            },
+            0xF2 => Instructions::Load {
+                op1: Operand::Register(Register::A),
+                op2: Operand::RegisterHighAddressU8(Register::C),
+            },
             0xF3 => Instructions::Di,
+            0xF5 => Instructions::Push {
+                op: Operand::Register(Register::AF),
+            },
+            0xF6 => {
+                result += 1;
+
+                Instructions::Or {
+                    op1: Operand::Register(Register::A),
+                    op2: Operand::AddressU8(pc.wrapping_add(1)),
+                }
+            }
+            0xF8 => {
+                result += 1;
+
+                Instructions::AddSp {
+                    op: Operand::Register(Register::HL),
+                    by: Operand::AddressU8(pc.wrapping_add(1)),
+                }
+            }
+            0xF9 => Instructions::Load {
+                op1: Operand::Register(Register::SP),
+                op2: Operand::Register(Register::HL),
+            },
+            0xFA => {
+                result += 2;
+
+                Instructions::Load {
+                    op1: Operand::Register(Register::A),
+                    op2: Operand::PointerU8(pc.wrapping_add(1)),
+                }
+            }
+            0xFB => Instructions::Ei,
+            0xFE => {
+                result += 1;
+
+                Instructions::Cp {
+                    op1: Operand::Register(Register::A),
+                    op2: Operand::AddressU8(pc.wrapping_add(1)),
+                }
+            }
+            0xD3 | 0xDB | 0xDD | 0xE3 | 0xE4 | 0xEB | 0xEC | 0xED | 0xF4
+            | 0xFC | 0xFD => Instructions::Lock { opcode },
             _ => Instructions::Undefined { opcode },
         };
         (instr, result)
@@ -485,16 +1288,48 @@ impl fmt::Display for Instructions {
             Instructions::Undefined { opcode } => {
                 write!(f, "Undefined {:04X}", opcode)
             }
+            Instructions::Lock { opcode } => write!(f, "Lock {:02X}", opcode),
             Instructions::Nop => write!(f, "Nop"),
             Instructions::Add { op1, op2 } => write!(f, "Add {}, {}", op1, op2),
+            Instructions::Adc { op1, op2 } => write!(f, "Adc {}, {}", op1, op2),
+            Instructions::Sub { op1, op2 } => write!(f, "Sub {}, {}", op1, op2),
+            Instructions::Sbc { op1, op2 } => write!(f, "Sbc {}, {}", op1, op2),
+            Instructions::And { op1, op2 } => write!(f, "And {}, {}", op1, op2),
             Instructions::Xor { op1, op2 } => write!(f, "Xor {}, {}", op1, op2),
+            Instructions::Or { op1, op2 } => write!(f, "Or {}, {}", op1, op2),
+            Instructions::Cp { op1, op2 } => write!(f, "Cp {}, {}", op1, op2),
+            Instructions::AddSp { op, by } => {
+                write!(f, "Load {}, SP+{}", op, by)
+            }
             Instructions::Jp { cod, op } => write!(f, "Jp {}, {}", cod, op),
+            Instructions::Call { cod, op } => {
+                write!(f, "Call {}, {}", cod, op)
+            }
+            Instructions::Ret { cod } => write!(f, "Ret {}", cod),
+            Instructions::Reti => write!(f, "Reti"),
+            Instructions::Rst { address } => write!(f, "Rst {:02X}", address),
+            Instructions::Push { op } => write!(f, "Push {}", op),
+            Instructions::Pop { op } => write!(f, "Pop {}", op),
             Instructions::Load { op1, op2 } => {
                 write!(f, "Load {}, {}", op1, op2)
             }
             Instructions::Inc { op } => write!(f, "Inc {}", op),
             Instructions::Dec { op } => write!(f, "Dec {}", op),
+            Instructions::Rlca => write!(f, "Rlca"),
+            Instructions::Rla => write!(f, "Rla"),
+            Instructions::Rrca => write!(f, "Rrca"),
+            Instructions::Rra => write!(f, "Rra"),
+            Instructions::Rlc { op } => write!(f, "Rlc {}", op),
+            Instructions::Rl { op } => write!(f, "Rl {}", op),
+            Instructions::Rrc { op } => write!(f, "Rrc {}", op),
+            Instructions::Rr { op } => write!(f, "Rr {}", op),
             Instructions::Sla { op } => write!(f, "Sla {}", op),
+            Instructions::Sra { op } => write!(f, "Sra {}", op),
+            Instructions::Swap { op } => write!(f, "Swap {}", op),
+            Instructions::Srl { op } => write!(f, "Srl {}", op),
+            Instructions::Bit { bit, op } => write!(f, "Bit {}, {}", bit, op),
+            Instructions::Res { bit, op } => write!(f, "Res {}, {}", bit, op),
+            Instructions::Set { bit, op } => write!(f, "Set {}, {}", bit, op),
             Instructions::Stacked { stack } => {
                 for instr in stack.iter() {
                     let _ = write!(f, "{} -- ", instr);
@@ -502,35 +1337,173 @@ impl fmt::Display for Instructions {
 
                 write!(f, "")
             }
+            Instructions::Daa => write!(f, "Daa"),
+            Instructions::Scf => write!(f, "Scf"),
+            Instructions::Ccf => write!(f, "Ccf"),
             Instructions::Cpl => write!(f, "Cpl"),
-            Instructions::Di => write!(f, "Di -- Not functional"),
+            Instructions::Halt => write!(f, "Halt"),
+            Instructions::Stop => write!(f, "Stop"),
+            Instructions::Di => write!(f, "Di"),
+            Instructions::Ei => write!(f, "Ei"),
         }
     }
 }
 
 impl CPU {
-    pub fn step(&mut self) {
-        let opcode = self.memory.get_byte(self.pc).unwrap();
-        let (instruction, size) =
-            Instructions::decode(opcode as u8, &mut self.pc);
-        println!("0x{:04X} -- {}: {}", self.pc, size, instruction);
-        self.pc += size as u16;
+    fn set_flags(&mut self, z: bool, n: bool, h: bool, c: bool) {
+        self.f =
+            (z as u8) << 7 | (n as u8) << 6 | (h as u8) << 5 | (c as u8) << 4;
+    }
+
+    fn push(&mut self, value: u16) {
+        self.sp = self.sp.wrapping_sub(2);
+        self.memory.set_word(self.sp, value);
+    }
+
+    fn pop(&mut self) -> u16 {
+        let value = self.memory.get_word(self.sp);
+        self.sp = self.sp.wrapping_add(2);
+        value
+    }
+
+    /// Jumps to the vector of the highest priority pending interrupt,
+    /// returning the cycles spent doing it.
+    fn handle_interrupts(&mut self) -> u32 {
+        if !self.ime {
+            return 0;
+        }
+
+        match self.memory.pending_interrupt() {
+            Some(interrupt) => {
+                self.ime = false;
+                self.memory.acknowledge_interrupt(interrupt);
+                // After EI; HALT the bugged fetch is the return address
+                // instead, so the handler returns to the HALT.
+                let pc = if self.halt_bug {
+                    self.halt_bug = false;
+                    self.pc.wrapping_sub(1)
+                } else {
+                    self.pc
+                };
+                self.push(pc);
+                self.pc = interrupt.vector();
+                self.cycles += 20;
+                20
+            }
+            None => 0,
+        }
+    }
+
+    /// Runs a single instruction, returning the T-cycles it took, and lets
+    /// the rest of the system catch up.
+    pub fn step(&mut self) -> u32 {
+        let cycles = self.run();
+        self.memory.tick(cycles);
+        cycles
+    }
+
+    fn run(&mut self) -> u32 {
+        match self.state {
+            State::Locked => return self.idle(),
+            State::Stopped => {
+                if self.memory.take_joypad_pressed() {
+                    self.state = State::Running;
+                } else {
+                    return self.idle();
+                }
+            }
+            State::Halted => {
+                if self.memory.pending_interrupt().is_some() {
+                    self.state = State::Running;
+                } else {
+                    return self.idle();
+                }
+            }
+            State::Running => {}
+        }
+
+        let cycles = self.handle_interrupts();
+        if cycles > 0 {
+            return cycles;
+        }
+
+        // EI only takes effect after the instruction that follows it.
+        let enable_interrupts = self.ime_scheduled;
+
+        // The HALT bug reads the byte at PC twice, as if PC was one behind.
+        let pc = if self.halt_bug {
+            self.halt_bug = false;
+            self.pc.wrapping_sub(1)
+        } else {
+            self.pc
+        };
+
+        let start = self.cycles;
+        let opcode = self.memory.get_byte(self.pc);
+        let (instruction, size) = if opcode == 0xCB {
+            let opcode = self.memory.get_byte(pc.wrapping_add(1));
+            self.cycles += cb_cycles(opcode) as u64;
+            Instructions::decode_cb(opcode)
+        } else {
+            self.cycles += CYCLES[opcode as usize] as u64;
+            Instructions::decode(opcode, &pc)
+        };
+        if self.trace {
+            println!("0x{:04X} -- {}: {}", self.pc, size, instruction);
+        }
+        self.pc = pc.wrapping_add(size as u16);
         instruction.execute(self);
+
+        if enable_interrupts && self.ime_scheduled {
+            self.ime = true;
+            self.ime_scheduled = false;
+        }
+
+        (self.cycles - start) as u32
+    }
+
+    /// Lets a machine cycle pass without running anything.
+    fn idle(&mut self) -> u32 {
+        self.cycles += 4;
+        4
+    }
+
+    /// T-cycles elapsed since the CPU was created.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    pub fn memory(&self) -> &memory::Memory {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut memory::Memory {
+        &mut self.memory
     }
 
     pub fn new(memory: memory::Memory) -> CPU {
         CPU {
             memory,
             pc: 0x100,
+            sp: 0xFFFE,
             a: 0x01,
             b: 0x00,
             c: 0x13,
             d: 0x00,
-            e: 0xD8_u8 as i8,
+            e: 0xD8,
             h: 0x01,
             l: 0x4D,
-            fz: false,
-            fc: false,
+            f: 0xB0,
+            ime: false,
+            ime_scheduled: false,
+            halt_bug: false,
+            state: State::Running,
+            cycles: 0,
+            trace: false,
         }
     }
 }
@@ -0,0 +1,149 @@
+use std::collections::VecDeque;
+
+/// Steps of the background fetcher, all but `Push` take two T-cycles.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Step {
+    Tile,
+    DataLow,
+    DataHigh,
+    /// Waits for the background FIFO to empty before refilling it.
+    Push,
+}
+
+#[derive(Clone, Copy)]
+pub struct ObjectPixel {
+    /// Color number, 0 being transparent.
+    pub color: u8,
+    /// OBP1 instead of OBP0.
+    pub high_palette: bool,
+    pub behind_background: bool,
+}
+
+const TRANSPARENT: ObjectPixel = ObjectPixel {
+    color: 0,
+    high_palette: false,
+    behind_background: false,
+};
+
+/// Pixel FIFOs and background fetcher state while drawing a line.
+pub struct Fifo {
+    /// Background and window color numbers.
+    pub background: VecDeque<u8>,
+    pub objects: VecDeque<ObjectPixel>,
+    pub step: Step,
+    /// T-cycles spent on the current step.
+    pub step_cycles: u8,
+    /// Tile column of the next fetch, from the left of the background
+    /// viewport or of the window.
+    pub tile_x: u8,
+    pub tile: u8,
+    pub low: u8,
+    pub high: u8,
+    /// The fetcher switched to the window on this line.
+    pub window: bool,
+    /// Pixels already sent to the LCD.
+    pub x: u8,
+    /// Pixels still to drop for the SCX fine scrolling.
+    pub discard: u8,
+    /// The first tile of each line is fetched twice, the first one is
+    /// thrown away.
+    pub first_fetch: bool,
+}
+
+impl Fifo {
+    /// Gets ready for a new line.
+    pub fn start(&mut self, scx: u8) {
+        self.background.clear();
+        self.objects.clear();
+        self.restart_fetcher();
+        self.tile_x = 0;
+        self.window = false;
+        self.x = 0;
+        self.discard = scx % 8;
+        self.first_fetch = true;
+    }
+
+    /// Throws away the background pixels and fetches the window instead.
+    pub fn start_window(&mut self) {
+        self.background.clear();
+        self.restart_fetcher();
+        self.tile_x = 0;
+        self.window = true;
+    }
+
+    fn restart_fetcher(&mut self) {
+        self.step = Step::Tile;
+        self.step_cycles = 0;
+    }
+
+    /// Moves the fetcher on to `next` once the current step took its two
+    /// T-cycles, returning whether it did.
+    pub fn advance(&mut self, next: Step) -> bool {
+        self.step_cycles += 1;
+        if self.step_cycles < 2 {
+            return false;
+        }
+        self.step_cycles = 0;
+        self.step = next;
+        true
+    }
+
+    /// Pushes the fetched row once the background FIFO is empty.
+    pub fn push(&mut self) {
+        if !self.background.is_empty() {
+            return;
+        }
+
+        if self.first_fetch {
+            self.first_fetch = false;
+        } else {
+            for bit in (0..8).rev() {
+                let low = self.low >> bit & 0x01;
+                let high = self.high >> bit & 0x01;
+                self.background.push_back(high << 1 | low);
+            }
+            self.tile_x = self.tile_x.wrapping_add(1);
+        }
+        self.step = Step::Tile;
+    }
+
+    /// Mixes a fetched sprite row into the object FIFO. Pixels already
+    /// there come from sprites with higher priority, so only transparent
+    /// ones are replaced. `skip` pixels fall off the left of the screen.
+    pub fn merge_sprite(
+        &mut self,
+        colors: [u8; 8],
+        skip: usize,
+        template: ObjectPixel,
+    ) {
+        while self.objects.len() < 8 - skip {
+            self.objects.push_back(TRANSPARENT);
+        }
+
+        for (slot, color) in colors[skip..].iter().enumerate() {
+            if self.objects[slot].color == 0 {
+                self.objects[slot] = ObjectPixel {
+                    color: *color,
+                    ..template
+                };
+            }
+        }
+    }
+
+    pub fn new() -> Fifo {
+        Fifo {
+            background: VecDeque::with_capacity(16),
+            objects: VecDeque::with_capacity(8),
+            step: Step::Tile,
+            step_cycles: 0,
+            tile_x: 0,
+            tile: 0,
+            low: 0,
+            high: 0,
+            window: false,
+            x: 0,
+            discard: 0,
+            first_fetch: true,
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use audio::{to_i16, Sink};
+use cpal;
+
+/// Delay the host backend aims for between the APU and the speakers.
+const TARGET_LATENCY: f64 = 0.06;
+/// Largest change to the sample rate the rate control can make, small
+/// enough that the pitch change is not heard.
+const MAX_RATE_DEVIATION: f64 = 0.005;
+
+/// Plays the samples on the default output device.
+///
+/// Samples go through a ring buffer to the audio thread. The frontend paces
+/// the emulation with the wall clock, which still drifts a little from the
+/// sound card's, so the rate is nudged to keep the buffer around
+/// `TARGET_LATENCY` instead of letting it run dry or overflow.
+pub struct Host {
+    buffer: Arc<Mutex<VecDeque<f32>>>,
+    sample_rate: u32,
+    /// Samples in the buffer at the target latency.
+    target: usize,
+}
+
+impl Host {
+    pub fn new() -> Result<Host, String> {
+        let device = cpal::default_output_device()
+            .ok_or_else(|| "no output device".to_string())?;
+        let format = device
+            .default_output_format()
+            .map_err(|err| err.to_string())?;
+        let event_loop = cpal::EventLoop::new();
+        let stream = event_loop
+            .build_output_stream(&device, &format)
+            .map_err(|err| err.to_string())?;
+
+        let sample_rate = format.sample_rate.0;
+        let target = (sample_rate as f64 * TARGET_LATENCY) as usize * 2;
+        let buffer = Arc::new(Mutex::new(VecDeque::with_capacity(target * 2)));
+
+        event_loop.play_stream(stream);
+        let channels = format.channels as usize;
+        let output = buffer.clone();
+        thread::spawn(move || {
+            event_loop.run(move |_, data| {
+                if let cpal::StreamData::Output { buffer } = data {
+                    play(&mut output.lock().unwrap(), buffer, channels);
+                }
+            });
+        });
+
+        Ok(Host {
+            buffer,
+            sample_rate,
+            target,
+        })
+    }
+}
+
+impl Sink for Host {
+    fn queue(&mut self, samples: &[f32]) {
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.extend(samples);
+        // Past twice the target latency, drop the oldest frames so the delay
+        // stays bounded.
+        let overflow = buffer.len().saturating_sub(self.target * 2) & !0x01;
+        buffer.drain(..overflow);
+    }
+
+    fn sample_rate(&self) -> f64 {
+        let buffered = self.buffer.lock().unwrap().len() as f64;
+        let target = self.target as f64;
+        let error = ((buffered - target) / target).clamp(-1.0, 1.0);
+        self.sample_rate as f64 * (1.0 - MAX_RATE_DEVIATION * error)
+    }
+}
+
+/// Fills the device buffer with frames from the ring buffer, repeating the
+/// last frame if it runs dry.
+fn play(
+    samples: &mut VecDeque<f32>,
+    buffer: cpal::UnknownTypeOutputBuffer,
+    channels: usize,
+) {
+    let mut frame = [0.0; 2];
+    let mut next = || {
+        if samples.len() >= 2 {
+            frame =
+                [samples.pop_front().unwrap(), samples.pop_front().unwrap()];
+        }
+        frame
+    };
+
+    match buffer {
+        cpal::UnknownTypeOutputBuffer::F32(mut buffer) => {
+            fill(&mut buffer, channels, &mut next, |sample| sample)
+        }
+        cpal::UnknownTypeOutputBuffer::I16(mut buffer) => {
+            fill(&mut buffer, channels, &mut next, to_i16)
+        }
+        cpal::UnknownTypeOutputBuffer::U16(mut buffer) => {
+            fill(&mut buffer, channels, &mut next, |sample| {
+                (to_i16(sample) as i32 + 0x8000) as u16
+            })
+        }
+    }
+}
+
+/// Writes stereo frames to a buffer with `channels` samples per frame. Mono
+/// devices get the average of both sides and extra channels stay silent.
+fn fill<T, F, C>(buffer: &mut [T], channels: usize, next: &mut F, convert: C)
+where
+    T: Copy,
+    F: FnMut() -> [f32; 2],
+    C: Fn(f32) -> T,
+{
+    for output in buffer.chunks_mut(channels) {
+        let [left, right] = next();
+        for (channel, sample) in output.iter_mut().enumerate() {
+            *sample = convert(match channel {
+                _ if channels == 1 => (left + right) / 2.0,
+                0 => left,
+                1 => right,
+                _ => 0.0,
+            });
+        }
+    }
+}
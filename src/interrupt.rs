@@ -0,0 +1,48 @@
+/// Interrupt sources, declared from the highest to the lowest priority.
+#[derive(Clone, Copy)]
+pub enum Interrupt {
+    VBlank,
+    Stat,
+    Timer,
+    Serial,
+    Joypad,
+}
+
+const PRIORITY: [Interrupt; 5] = [
+    Interrupt::VBlank,
+    Interrupt::Stat,
+    Interrupt::Timer,
+    Interrupt::Serial,
+    Interrupt::Joypad,
+];
+
+impl Interrupt {
+    /// Bit of the interrupt inside IE and IF.
+    pub fn mask(&self) -> u8 {
+        match self {
+            Interrupt::VBlank => 0x01,
+            Interrupt::Stat => 0x02,
+            Interrupt::Timer => 0x04,
+            Interrupt::Serial => 0x08,
+            Interrupt::Joypad => 0x10,
+        }
+    }
+
+    pub fn vector(&self) -> u16 {
+        match self {
+            Interrupt::VBlank => 0x40,
+            Interrupt::Stat => 0x48,
+            Interrupt::Timer => 0x50,
+            Interrupt::Serial => 0x58,
+            Interrupt::Joypad => 0x60,
+        }
+    }
+
+    /// Highest priority interrupt that is both enabled and requested.
+    pub fn pending(enable: u8, flag: u8) -> Option<Interrupt> {
+        PRIORITY
+            .iter()
+            .find(|interrupt| enable & flag & interrupt.mask() != 0)
+            .cloned()
+    }
+}
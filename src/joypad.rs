@@ -0,0 +1,97 @@
+use interrupt::Interrupt;
+
+#[derive(Clone, Copy)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    /// Bit of the button inside `Joypad::pressed`, directions in the lower
+    /// nibble and actions in the upper one, both in P1 order.
+    fn mask(&self) -> u8 {
+        match self {
+            Button::Right => 0x01,
+            Button::Left => 0x02,
+            Button::Up => 0x04,
+            Button::Down => 0x08,
+            Button::A => 0x10,
+            Button::B => 0x20,
+            Button::Select => 0x40,
+            Button::Start => 0x80,
+        }
+    }
+}
+
+/// P1/JOYP (0xFF00), where the buttons are read four at a time.
+pub struct Joypad {
+    /// P14 and P15 as written, a low bit selects the directions and the
+    /// actions respectively.
+    select: u8,
+    pressed: u8,
+}
+
+impl Joypad {
+    pub fn read(&self) -> u8 {
+        0xC0 | self.select | self.lines()
+    }
+
+    /// Selects the buttons to read, returning the IF bits of the interrupts
+    /// it requested.
+    pub fn write(&mut self, value: u8) -> u8 {
+        let lines = self.lines();
+        self.select = value & 0x30;
+        self.interrupt(lines)
+    }
+
+    /// Returns the IF bits of the interrupts the press requested.
+    pub fn press(&mut self, button: Button) -> u8 {
+        let lines = self.lines();
+        self.pressed |= button.mask();
+        self.interrupt(lines)
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.pressed &= !button.mask();
+    }
+
+    /// P10-P13, low for the pressed buttons in the selected groups.
+    fn lines(&self) -> u8 {
+        let mut pressed = 0;
+        if self.select & 0x10 == 0 {
+            pressed |= self.pressed & 0x0F;
+        }
+        if self.select & 0x20 == 0 {
+            pressed |= self.pressed >> 4;
+        }
+        !pressed & 0x0F
+    }
+
+    /// The interrupt fires when any of P10-P13 goes from high to low.
+    fn interrupt(&self, previous: u8) -> u8 {
+        if previous & !self.lines() != 0 {
+            Interrupt::Joypad.mask()
+        } else {
+            0
+        }
+    }
+
+    pub fn new() -> Joypad {
+        Joypad {
+            select: 0x30,
+            pressed: 0x00,
+        }
+    }
+}
+
+impl Default for Joypad {
+    fn default() -> Joypad {
+        Joypad::new()
+    }
+}
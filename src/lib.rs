@@ -0,0 +1,19 @@
+#[cfg(feature = "host-audio")]
+extern crate cpal;
+extern crate hound;
+
+pub mod apu;
+pub mod audio;
+pub mod cartridge;
+pub mod cpu;
+mod fifo;
+#[cfg(feature = "host-audio")]
+pub mod host;
+pub mod interrupt;
+pub mod joypad;
+mod mbc;
+pub mod memory;
+pub mod ppu;
+pub mod rtc;
+pub mod save;
+mod timer;
@@ -1,15 +1,48 @@
 extern crate clap;
+extern crate gb_emulator;
 extern crate glium;
 
 use clap::{App, Arg};
 
+#[cfg(feature = "host-audio")]
+use gb_emulator::host;
+use gb_emulator::{audio, cartridge, cpu, joypad, memory, ppu, rtc, save};
+
 use glium::glutin;
+use glium::glutin::event::{ElementState, Event, VirtualKeyCode, WindowEvent};
+use glium::glutin::event_loop::{ControlFlow, EventLoop};
+use glium::glutin::platform::run_return::EventLoopExtRunReturn;
+use glium::texture::RawImage2d;
+use glium::uniforms::MagnifySamplerFilter;
 use glium::Surface;
 use std::fs::File;
 use std::io::prelude::*;
+use std::path::Path;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
 
-mod cpu;
-mod memory;
+/// T-cycles em um quadro, 154 linhas de 456 ciclos.
+const CYCLES_PER_FRAME: u64 = 70_224;
+/// T-cycles entre duas escritas do save, uns 5 segundos.
+const SAVE_INTERVAL: u64 = 5 * cpu::CYCLES_PER_SECOND as u64;
+/// Taxa de amostragem do áudio sem placa de som, em Hz.
+const SAMPLE_RATE: u32 = 48_000;
+/// Saída de áudio padrão, a placa de som só existe com a feature host-audio.
+const AUDIO: &str = if cfg!(feature = "host-audio") {
+    "host"
+} else {
+    "null"
+};
+/// Tamanho de cada pixel da tela na janela.
+const SCALE: u32 = 3;
+/// Cores dos tons, do mais claro ao mais escuro.
+const SHADES: [[u8; 3]; 4] = [
+    [0xE0, 0xF8, 0xD0],
+    [0x88, 0xC0, 0x70],
+    [0x34, 0x68, 0x56],
+    [0x08, 0x18, 0x20],
+];
 
 fn main() {
     let matches = App::new("RustBoy")
@@ -24,6 +57,72 @@ fn main() {
                 .takes_value(true)
                 .required(true),
         )
+        .arg(
+            Arg::with_name("rtc")
+                .long("rtc")
+                .help("Clock driving the cartridge RTC")
+                .takes_value(true)
+                .possible_values(&["host", "emulated"])
+                .default_value("host"),
+        )
+        .arg(
+            Arg::with_name("ppu")
+                .long("ppu")
+                .help("Renderer, fifo follows registers changed mid line")
+                .takes_value(true)
+                .possible_values(&["scanline", "fifo"])
+                .default_value("scanline"),
+        )
+        .arg(
+            Arg::with_name("save")
+                .short("s")
+                .long("save")
+                .help("Save file, defaults to the ROM path with .sav")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("audio")
+                .long("audio")
+                .help("Audio output, wav writes to --audio-file")
+                .takes_value(true)
+                .possible_values(&["host", "null", "wav"])
+                .default_value(AUDIO),
+        )
+        .arg(
+            Arg::with_name("audio-file")
+                .long("audio-file")
+                .help("WAV file written by --audio wav")
+                .takes_value(true)
+                .required_if("audio", "wav"),
+        )
+        .arg(
+            Arg::with_name("headless")
+                .long("headless")
+                .help("Runs without a window, as fast as possible"),
+        )
+        .arg(
+            Arg::with_name("frames")
+                .long("frames")
+                .help("Stops after this many frames")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("trace")
+                .long("trace")
+                .help("Prints every instruction run, very slow"),
+        )
+        .arg(
+            Arg::with_name("record-audio")
+                .long("record-audio")
+                .help("Records the APU output to a WAV, or .raw/.pcm, file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("record-stems")
+                .long("record-stems")
+                .help("Also records each channel to its own file")
+                .requires("record-audio"),
+        )
         .get_matches();
 
     // It's safe to unwrap here because this argument is required.
@@ -37,40 +136,331 @@ fn main() {
 
     // Lê todo o arquivo e coloca na memoria (rom)
     let mut rom = Vec::new();
-    let rom_size = match rom_file.read_to_end(&mut rom) {
-        Ok(size) => size,
-        Err(_) => 0,
+    if let Err(err) = rom_file.read_to_end(&mut rom) {
+        panic!("Deu bosta na leitura do arquivo: {}", err);
+    }
+
+    let clock = match matches.value_of("rtc") {
+        Some("emulated") => rtc::Clock::Emulated,
+        _ => rtc::Clock::Host,
     };
 
-    let mem = memory::Memory::new(rom);
-    let mut cpu = cpu::CPU::new(mem);
-    loop {
-        cpu.step();
+    // Valida o cabeçalho e escolhe o controlador do cartucho
+    let mut cartridge = match cartridge::Cartridge::new(rom, clock) {
+        Ok(cartridge) => cartridge,
+        Err(err) => {
+            eprintln!("ROM inválida: {}", err);
+            process::exit(1);
+        }
+    };
+    println!("{}", cartridge.header);
+    for warning in cartridge.warnings() {
+        match *warning {
+            cartridge::Warning::GlobalChecksum => {
+                eprintln!("Aviso: o checksum global não confere")
+            }
+            cartridge::Warning::RomSize(header, found) => eprintln!(
+                "Aviso: o cabeçalho diz {} bytes de ROM, mas o arquivo tem {}",
+                header, found
+            ),
+        }
     }
 
+    // Carrega o save de cartuchos com bateria
+    let save_file = if cartridge.has_battery() {
+        let save_file = match matches.value_of("save") {
+            Some(path) => save::SaveFile::new(path),
+            None => save::SaveFile::beside(Path::new(rom_path)),
+        };
+        if let Err(err) = save_file.load(&mut cartridge) {
+            eprintln!(
+                "Não foi possível ler {}: {}",
+                save_file.path().display(),
+                err
+            );
+            process::exit(1);
+        }
+        Some(save_file)
+    } else {
+        None
+    };
+
+    let renderer = match matches.value_of("ppu") {
+        Some("fifo") => ppu::Renderer::Fifo,
+        _ => ppu::Renderer::Scanline,
+    };
+
+    let frames = match matches.value_of("frames").map(str::parse::<u64>) {
+        Some(Ok(frames)) => Some(frames),
+        Some(Err(err)) => {
+            eprintln!("Número de quadros inválido: {}", err);
+            process::exit(1);
+        }
+        None => None,
+    };
+
+    // Sem janela o áudio é descartado, a não ser que peçam outra saída
+    let headless = matches.is_present("headless");
+    let audio = if headless && matches.occurrences_of("audio") == 0 {
+        "null"
+    } else {
+        // It's safe to unwrap here because this argument has a default.
+        matches.value_of("audio").unwrap()
+    };
+
+    // Escolhe para onde vai o áudio, sem placa de som ele é descartado
+    let mut sink: Box<dyn audio::Sink> = match audio {
+        "null" => Box::new(audio::Null::new(SAMPLE_RATE)),
+        "wav" => {
+            // It's safe to unwrap here because wav requires the file.
+            let path = matches.value_of("audio-file").unwrap();
+            match audio::Wav::create(Path::new(path), SAMPLE_RATE) {
+                Ok(wav) => Box::new(wav),
+                Err(err) => {
+                    eprintln!("Não foi possível criar {}: {}", path, err);
+                    process::exit(1);
+                }
+            }
+        }
+        _ => match host_sink() {
+            Ok(host) => host,
+            Err(err) => {
+                eprintln!("Sem áudio: {}", err);
+                Box::new(audio::Null::new(SAMPLE_RATE))
+            }
+        },
+    };
+    // A placa de som consome o áudio em tempo real, então a emulação também
+    // precisa seguir o relógio
+    let paced = !headless || audio == "host";
+
+    let mem = memory::Memory::new(cartridge, renderer, SAMPLE_RATE);
+    let mut cpu = cpu::CPU::new(mem);
+    cpu.set_trace(matches.is_present("trace"));
+    cpu.memory_mut()
+        .apu_mut()
+        .set_sample_rate(sink.sample_rate());
+
+    // Grava o áudio a uma taxa fixa, independente da saída
+    let mut recorder = matches.value_of("record-audio").map(|path| {
+        let stems = matches.is_present("record-stems");
+        match audio::Recorder::create(Path::new(path), SAMPLE_RATE, stems) {
+            Ok(recorder) => {
+                cpu.memory_mut().apu_mut().record(recorder.recording());
+                recorder
+            }
+            Err(err) => {
+                eprintln!("Não foi possível criar {}: {}", path, err);
+                process::exit(1);
+            }
+        }
+    });
+
     // Instancia os objetos necessarios para tela
-    let mut events_loop = glutin::EventsLoop::new();
-    let window = glutin::WindowBuilder::new()
-        .with_dimensions(240, 160)
-        .with_title("GBA Emulator");
-    let context = glutin::ContextBuilder::new();
-    let display = glium::Display::new(window, context, &events_loop).unwrap();
-
-    let mut closed = false;
-
-    // Muda a cor do fundo para azul
-    let mut target = display.draw();
-    target.clear_color(0.0, 0.0, 1., 1.);
-    target.finish().unwrap();
-
-    // Loop de execução do programa
-    while !closed {
-        events_loop.poll_events(|ev| match ev {
-            glutin::Event::WindowEvent { event, .. } => match event {
-                glutin::WindowEvent::Closed => closed = true,
+    let mut screen = if headless {
+        None
+    } else {
+        match Screen::new() {
+            Ok(screen) => Some(screen),
+            Err(err) => {
+                eprintln!("Não foi possível abrir a janela: {}", err);
+                eprintln!("Use --headless para rodar sem tela.");
+                process::exit(1);
+            }
+        }
+    };
+
+    // Loop de execução do programa, no ritmo do relógio e não do monitor
+    let frame_time = Duration::from_nanos(
+        CYCLES_PER_FRAME * 1_000_000_000 / cpu::CYCLES_PER_SECOND as u64,
+    );
+    let mut next_frame = Instant::now();
+    let mut frame = 0;
+    let mut saved_at = 0;
+    while frames.is_none_or(|frames| frame < frames) {
+        // Em velocidade dupla a CPU roda o dobro de ciclos por quadro
+        let speed = if cpu.memory().double_speed() { 2 } else { 1 };
+        let frame_end = cpu.cycles() + CYCLES_PER_FRAME * speed;
+        while cpu.cycles() < frame_end {
+            cpu.step();
+        }
+        frame += 1;
+
+        if let Some(ref save_file) = save_file {
+            if cpu.cycles() - saved_at >= SAVE_INTERVAL {
+                saved_at = cpu.cycles();
+                flush(save_file, &mut cpu);
+            }
+        }
+
+        // Envia o áudio do quadro e ajusta a taxa ao que a saída consome
+        let apu = cpu.memory_mut().apu_mut();
+        sink.queue(&apu.take_samples());
+        apu.set_sample_rate(sink.sample_rate());
+        record(&mut recorder, &mut cpu);
+
+        if let Some(ref mut screen) = screen {
+            screen.draw(cpu.memory().ppu().framebuffer());
+            if screen.poll_events(cpu.memory_mut()) {
+                break;
+            }
+        }
+
+        if paced {
+            wait(&mut next_frame, frame_time);
+        }
+    }
+
+    // Salva antes de sair
+    if let Some(ref save_file) = save_file {
+        flush(save_file, &mut cpu);
+    }
+    record(&mut recorder, &mut cpu);
+    if let Some(recorder) = recorder {
+        if let Err(err) = recorder.finish() {
+            eprintln!("Não foi possível terminar a gravação: {}", err);
+        }
+    }
+    if let Err(err) = sink.finish() {
+        eprintln!("Não foi possível terminar o áudio: {}", err);
+    }
+}
+
+/// Escreve o que a APU gravou, parando a gravação se der erro.
+fn record(recorder: &mut Option<audio::Recorder>, cpu: &mut cpu::CPU) {
+    let result = match (
+        recorder.as_mut(),
+        cpu.memory_mut().apu_mut().recording_mut(),
+    ) {
+        (Some(recorder), Some(recording)) => recorder.write(recording),
+        _ => return,
+    };
+    if let Err(err) = result {
+        eprintln!("Não foi possível gravar o áudio: {}", err);
+        *recorder = None;
+    }
+}
+
+/// Espera o momento do próximo quadro. Se a emulação ficar para trás,
+/// recomeça a contar de agora em vez de correr para compensar.
+fn wait(next_frame: &mut Instant, frame_time: Duration) {
+    *next_frame += frame_time;
+    let now = Instant::now();
+    if *next_frame > now {
+        thread::sleep(*next_frame - now);
+    } else if now - *next_frame > frame_time * 4 {
+        *next_frame = now;
+    }
+}
+
+/// Toca o áudio na placa de som padrão.
+#[cfg(feature = "host-audio")]
+fn host_sink() -> Result<Box<dyn audio::Sink>, String> {
+    Ok(Box::new(host::Host::new()?))
+}
+
+#[cfg(not(feature = "host-audio"))]
+fn host_sink() -> Result<Box<dyn audio::Sink>, String> {
+    Err("compilado sem a feature host-audio".to_string())
+}
+
+/// Janela onde a tela é desenhada e de onde vem o teclado.
+struct Screen {
+    event_loop: EventLoop<()>,
+    display: glium::Display,
+}
+
+impl Screen {
+    fn new() -> Result<Screen, glium::backend::glutin::DisplayCreationError> {
+        let event_loop = EventLoop::new();
+        let window = glutin::window::WindowBuilder::new()
+            .with_inner_size(glutin::dpi::LogicalSize::new(
+                ppu::SCREEN_WIDTH as u32 * SCALE,
+                ppu::SCREEN_HEIGHT as u32 * SCALE,
+            ))
+            .with_title("RustBoy");
+        // Sem vsync, quem dita o ritmo é o relógio
+        let context = glutin::ContextBuilder::new().with_vsync(false);
+        let display = glium::Display::new(window, context, &event_loop)?;
+
+        Ok(Screen {
+            event_loop,
+            display,
+        })
+    }
+
+    /// Desenha o framebuffer da PPU esticado na janela toda.
+    fn draw(&self, framebuffer: &[u8]) {
+        let pixels: Vec<u8> = framebuffer
+            .iter()
+            .flat_map(|shade| SHADES[*shade as usize].iter().cloned())
+            .collect();
+        let image = RawImage2d::from_raw_rgb_reversed(
+            &pixels,
+            (ppu::SCREEN_WIDTH as u32, ppu::SCREEN_HEIGHT as u32),
+        );
+        let texture = glium::Texture2d::new(&self.display, image).unwrap();
+
+        let target = self.display.draw();
+        texture
+            .as_surface()
+            .fill(&target, MagnifySamplerFilter::Nearest);
+        target.finish().unwrap();
+    }
+
+    /// Trata os eventos pendentes, retornando se a janela foi fechada.
+    fn poll_events(&mut self, memory: &mut memory::Memory) -> bool {
+        let mut closed = false;
+        self.event_loop
+            .run_return(|event, _, control_flow| match event {
+                Event::WindowEvent { event, .. } => match event {
+                    WindowEvent::CloseRequested => closed = true,
+                    WindowEvent::KeyboardInput { input, .. } => {
+                        let button =
+                            match input.virtual_keycode.and_then(button) {
+                                Some(button) => button,
+                                None => return,
+                            };
+                        match input.state {
+                            ElementState::Pressed => memory.press(button),
+                            ElementState::Released => memory.release(button),
+                        }
+                    }
+                    _ => (),
+                },
+                // Volta para a emulação assim que não há mais eventos
+                Event::MainEventsCleared => *control_flow = ControlFlow::Exit,
                 _ => (),
-            },
-            _ => (),
-        });
+            });
+        closed
+    }
+}
+
+/// Mapeia o teclado para os botões do GameBoy.
+fn button(key: VirtualKeyCode) -> Option<joypad::Button> {
+    use joypad::Button;
+
+    match key {
+        VirtualKeyCode::Right => Some(Button::Right),
+        VirtualKeyCode::Left => Some(Button::Left),
+        VirtualKeyCode::Up => Some(Button::Up),
+        VirtualKeyCode::Down => Some(Button::Down),
+        VirtualKeyCode::X => Some(Button::A),
+        VirtualKeyCode::Z => Some(Button::B),
+        VirtualKeyCode::Back => Some(Button::Select),
+        VirtualKeyCode::Return => Some(Button::Start),
+        _ => None,
+    }
+}
+
+/// Grava o save, avisando se der erro.
+fn flush(save_file: &save::SaveFile, cpu: &mut cpu::CPU) {
+    let cartridge = cpu.memory_mut().cartridge_mut();
+    if let Err(err) = save_file.flush(cartridge) {
+        eprintln!(
+            "Não foi possível salvar {}: {}",
+            save_file.path().display(),
+            err
+        );
     }
 }
@@ -0,0 +1,431 @@
+use cartridge::Header;
+use rtc::Rtc;
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+
+/// Memory bank controller of a cartridge, owning its ROM and RAM.
+///
+/// ROM addresses go from 0x0000 to 0x7FFF and RAM addresses from 0x0000 to
+/// 0x1FFF, relative to 0xA000.
+pub trait Mbc {
+    fn read_rom(&self, address: u16) -> u8;
+    /// Writes to ROM never reach it, they drive the controller registers.
+    fn write_rom(&mut self, address: u16, value: u8);
+    fn read_ram(&self, address: u16) -> u8;
+    fn write_ram(&mut self, address: u16, value: u8);
+    /// Lets time pass for controllers with a clock.
+    fn tick(&mut self, _cycles: u32) {}
+    /// Whole external RAM, in the layout of the raw `.sav` format.
+    fn ram(&self) -> &[u8];
+    fn ram_mut(&mut self) -> &mut [u8];
+    fn rtc(&mut self) -> Option<&mut Rtc> {
+        None
+    }
+}
+
+fn rom_byte(rom: &[u8], bank: usize, address: u16) -> u8 {
+    let banks = (rom.len() / ROM_BANK_SIZE).max(1);
+    let offset = (bank % banks) * ROM_BANK_SIZE;
+    let address = offset + (address as usize & (ROM_BANK_SIZE - 1));
+    rom.get(address).cloned().unwrap_or(0xFF)
+}
+
+/// 32 KiB cartridges without any controller, optionally with up to 8 KiB
+/// of RAM that is always enabled.
+pub struct RomOnly {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+}
+
+impl RomOnly {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> RomOnly {
+        RomOnly {
+            rom,
+            ram: vec![0; ram_size.min(RAM_BANK_SIZE)],
+        }
+    }
+}
+
+impl Mbc for RomOnly {
+    fn read_rom(&self, address: u16) -> u8 {
+        self.rom.get(address as usize).cloned().unwrap_or(0xFF)
+    }
+
+    fn write_rom(&mut self, _address: u16, _value: u8) {}
+
+    fn read_ram(&self, address: u16) -> u8 {
+        self.ram.get(address as usize).cloned().unwrap_or(0xFF)
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if let Some(byte) = self.ram.get_mut(address as usize) {
+            *byte = value;
+        }
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+}
+
+pub struct Mbc1 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    /// Lower 5 bits of the ROM bank (0x2000-0x3FFF).
+    bank1: u8,
+    /// RAM bank or upper ROM bank bits (0x4000-0x5FFF).
+    bank2: u8,
+    /// Mode 1 also applies `bank2` to 0x0000-0x3FFF and to the RAM.
+    mode: bool,
+    /// Multicarts wire only 4 bits of `bank1`, to pack 64 KiB games.
+    multicart: bool,
+}
+
+impl Mbc1 {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> Mbc1 {
+        // Multicarts repeat the Nintendo logo on each game's first bank.
+        let multicart = rom.len() == 0x100000
+            && Header::logo_valid(&rom[0x10 * ROM_BANK_SIZE..]);
+
+        Mbc1 {
+            rom,
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            bank1: 1,
+            bank2: 0,
+            mode: false,
+            multicart,
+        }
+    }
+
+    fn bank2_shift(&self) -> u8 {
+        if self.multicart {
+            4
+        } else {
+            5
+        }
+    }
+
+    fn ram_address(&self, address: u16) -> usize {
+        let bank = if self.mode { self.bank2 as usize } else { 0 };
+        (bank * RAM_BANK_SIZE + address as usize) % self.ram.len()
+    }
+}
+
+impl Mbc for Mbc1 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let bank2 = (self.bank2 << self.bank2_shift()) as usize;
+        if address < 0x4000 {
+            let bank = if self.mode { bank2 } else { 0 };
+            rom_byte(&self.rom, bank, address)
+        } else {
+            let bank1 = if self.multicart {
+                self.bank1 & 0x0F
+            } else {
+                self.bank1
+            };
+            rom_byte(&self.rom, bank2 | bank1 as usize, address)
+        }
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                // Bank 0 can not be selected here, it becomes bank 1 even
+                // when the upper bits would make it 0x20, 0x40 or 0x60.
+                self.bank1 = match value & 0x1F {
+                    0 => 1,
+                    bank => bank,
+                };
+            }
+            0x4000..=0x5FFF => self.bank2 = value & 0x03,
+            _ => self.mode = value & 0x01 != 0,
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return 0xFF;
+        }
+        self.ram[self.ram_address(address)]
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return;
+        }
+        let address = self.ram_address(address);
+        self.ram[address] = value;
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+}
+
+pub struct Mbc3 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    rtc: Option<Rtc>,
+    /// Enables both the RAM and the RTC registers.
+    ram_enabled: bool,
+    rom_bank: u8,
+    /// RAM banks are 0x00 to 0x03, RTC registers are 0x08 to 0x0C.
+    ram_bank: u8,
+}
+
+impl Mbc3 {
+    pub fn new(rom: Vec<u8>, ram_size: usize, rtc: Option<Rtc>) -> Mbc3 {
+        Mbc3 {
+            rom,
+            ram: vec![0; ram_size],
+            rtc,
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+        }
+    }
+
+    fn ram_address(&self, address: u16) -> usize {
+        (self.ram_bank as usize * RAM_BANK_SIZE + address as usize)
+            % self.ram.len()
+    }
+}
+
+impl Mbc for Mbc3 {
+    fn read_rom(&self, address: u16) -> u8 {
+        if address < 0x4000 {
+            rom_byte(&self.rom, 0, address)
+        } else {
+            rom_byte(&self.rom, self.rom_bank as usize, address)
+        }
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                self.rom_bank = match value & 0x7F {
+                    0 => 1,
+                    bank => bank,
+                };
+            }
+            0x4000..=0x5FFF => self.ram_bank = value & 0x0F,
+            _ => {
+                if let Some(ref mut rtc) = self.rtc {
+                    rtc.write_latch(value);
+                }
+            }
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+
+        match (self.ram_bank, &self.rtc) {
+            (0x00..=0x03, _) if !self.ram.is_empty() => {
+                self.ram[self.ram_address(address)]
+            }
+            (0x08..=0x0C, Some(rtc)) => rtc.read(self.ram_bank),
+            _ => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+
+        match (self.ram_bank, &mut self.rtc) {
+            (0x00..=0x03, _) if !self.ram.is_empty() => {
+                let address = self.ram_address(address);
+                self.ram[address] = value;
+            }
+            (0x08..=0x0C, Some(rtc)) => rtc.write(self.ram_bank, value),
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if let Some(ref mut rtc) = self.rtc {
+            rtc.tick(cycles);
+        }
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn rtc(&mut self) -> Option<&mut Rtc> {
+        self.rtc.as_mut()
+    }
+}
+
+/// MBC2 has 512 half bytes of RAM built in, and up to 256 KiB of ROM.
+pub struct Mbc2 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    rom_bank: u8,
+}
+
+impl Mbc2 {
+    pub fn new(rom: Vec<u8>) -> Mbc2 {
+        Mbc2 {
+            rom,
+            ram: vec![0; 0x200],
+            ram_enabled: false,
+            rom_bank: 1,
+        }
+    }
+}
+
+impl Mbc for Mbc2 {
+    fn read_rom(&self, address: u16) -> u8 {
+        if address < 0x4000 {
+            rom_byte(&self.rom, 0, address)
+        } else {
+            rom_byte(&self.rom, self.rom_bank as usize, address)
+        }
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        // Both registers live in 0x0000-0x3FFF, address bit 8 picks one.
+        if address >= 0x4000 {
+            return;
+        }
+
+        if address & 0x0100 == 0 {
+            self.ram_enabled = value & 0x0F == 0x0A;
+        } else {
+            self.rom_bank = match value & 0x0F {
+                0 => 1,
+                bank => bank,
+            };
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        // Only the lower nibble exists and the RAM echoes up to 0xBFFF.
+        self.ram[address as usize & 0x1FF] | 0xF0
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if self.ram_enabled {
+            self.ram[address as usize & 0x1FF] = value & 0x0F;
+        }
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+}
+
+/// MBC5 addresses up to 8 MiB of ROM with 9 bits bank numbers and 128 KiB
+/// of RAM.
+pub struct Mbc5 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    rom_bank: u16,
+    ram_bank: u8,
+    /// Rumble cartridges drive the motor with bit 3 of the RAM bank.
+    rumble: bool,
+}
+
+impl Mbc5 {
+    pub fn new(rom: Vec<u8>, ram_size: usize, rumble: bool) -> Mbc5 {
+        Mbc5 {
+            rom,
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            rumble,
+        }
+    }
+
+    fn ram_address(&self, address: u16) -> usize {
+        (self.ram_bank as usize * RAM_BANK_SIZE + address as usize)
+            % self.ram.len()
+    }
+}
+
+impl Mbc for Mbc5 {
+    fn read_rom(&self, address: u16) -> u8 {
+        if address < 0x4000 {
+            rom_byte(&self.rom, 0, address)
+        } else {
+            // Unlike the older controllers, bank 0 can be mapped here.
+            rom_byte(&self.rom, self.rom_bank as usize, address)
+        }
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x2FFF => {
+                self.rom_bank = self.rom_bank & 0x100 | value as u16
+            }
+            0x3000..=0x3FFF => {
+                self.rom_bank =
+                    self.rom_bank & 0xFF | (value as u16 & 0x01) << 8
+            }
+            0x4000..=0x5FFF => {
+                self.ram_bank = if self.rumble {
+                    value & 0x07
+                } else {
+                    value & 0x0F
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return 0xFF;
+        }
+        self.ram[self.ram_address(address)]
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return;
+        }
+        let address = self.ram_address(address);
+        self.ram[address] = value;
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+}
@@ -1,11 +1,55 @@
+use std::mem;
+
+use apu::Apu;
+use cartridge::Cartridge;
+use interrupt::Interrupt;
+use joypad::{Button, Joypad};
+use ppu::{Ppu, Renderer};
+use timer::Timer;
+
 pub struct Memory {
-    rom: Vec<u8>,
+    cartridge: Cartridge,
+    ppu: Ppu,
+    timer: Timer,
+    joypad: Joypad,
+    apu: Apu,
     ram: Vec<u8>,
+    io: Vec<u8>,
+    hram: Vec<u8>,
+    interrupt_flag: u8,
+    interrupt_enable: u8,
+    speed_switch: u8,
+    /// T-cycles of double speed not yet passed on to the components that
+    /// stay at normal speed.
+    double_speed_cycles: u32,
+    /// Whether a button press brought a P1 line low since the last check,
+    /// which wakes the CPU from STOP.
+    joypad_pressed: bool,
+    /// Last value written to the DMA register.
+    dma_register: u8,
+    dma: Option<Dma>,
+}
+
+/// OAM DMA transfer in progress.
+struct Dma {
+    source: u16,
+    /// T-cycles since the transfer was started.
+    cycles: u32,
 }
 
+/// Bytes a DMA transfer copies to the OAM.
+const DMA_LENGTH: u32 = 0xA0;
+
 enum Section {
     Rom,
+    VideoRam,
+    ExternalRam,
     Ram,
+    Oam,
+    Unusable,
+    Io,
+    HighRam,
+    InterruptEnable,
 }
 
 struct TranslatedAddress {
@@ -14,85 +58,251 @@ struct TranslatedAddress {
 }
 
 impl Memory {
-    fn translate_address(
-        address: u16,
-    ) -> Result<TranslatedAddress, &'static str> {
-        // println!("Requested {:04X}", address);
-        if address <= 0 {
-            Err("Memory must be higher than zero")
-        } else if address < 0x8000 {
-            Ok(TranslatedAddress {
-                section: Section::Rom,
-                address: address,
-            })
-        } else if address < 0xE000 && address > 0xC000 {
-            Ok(TranslatedAddress {
-                section: Section::Ram,
-                address: address - 0xC000,
-            })
-        } else if address < 0xFE00 && address > 0xE000 {
-            Ok(TranslatedAddress {
-                section: Section::Ram,
-                address: address - 0xE000,
-            })
-        } else {
-            Err("Not mapped yet")
+    fn translate_address(address: u16) -> TranslatedAddress {
+        let (section, base) = match address {
+            0x0000..=0x7FFF => (Section::Rom, 0x0000),
+            0x8000..=0x9FFF => (Section::VideoRam, 0x8000),
+            0xA000..=0xBFFF => (Section::ExternalRam, 0xA000),
+            0xC000..=0xDFFF => (Section::Ram, 0xC000),
+            // Echo of the work RAM
+            0xE000..=0xFDFF => (Section::Ram, 0xE000),
+            0xFE00..=0xFE9F => (Section::Oam, 0xFE00),
+            0xFEA0..=0xFEFF => (Section::Unusable, 0xFEA0),
+            0xFF00..=0xFF7F => (Section::Io, 0xFF00),
+            0xFF80..=0xFFFE => (Section::HighRam, 0xFF80),
+            0xFFFF => (Section::InterruptEnable, 0xFFFF),
+        };
+
+        TranslatedAddress {
+            section,
+            address: address - base,
         }
     }
 
-    pub fn get_byte(&self, address: u16) -> Result<i8, &'static str> {
-        let translate_address = Memory::translate_address(address)?;
+    pub fn get_byte(&self, address: u16) -> u8 {
+        if self.dma_blocks(address) {
+            return 0xFF;
+        }
+        self.read(address)
+    }
+
+    fn read(&self, address: u16) -> u8 {
+        let translate_address = Memory::translate_address(address);
+        let address = translate_address.address as usize;
         match translate_address.section {
-            Section::Rom => {
-                Ok(self.rom[translate_address.address as usize] as i8)
-            }
-            Section::Ram => {
-                Ok(self.ram[translate_address.address as usize] as i8)
-            }
+            Section::Rom => self.cartridge.read_rom(address as u16),
+            // Blocked reads see the bus floating high.
+            Section::VideoRam if !self.ppu.vram_accessible() => 0xFF,
+            Section::VideoRam => self.ppu.read_vram(address as u16),
+            Section::ExternalRam => self.cartridge.read_ram(address as u16),
+            Section::Ram => self.ram[address],
+            Section::Oam if !self.ppu.oam_accessible() => 0xFF,
+            Section::Oam => self.ppu.read_oam(address as u16),
+            Section::Unusable => 0x00,
+            Section::Io => self.read_io(address as u16),
+            Section::HighRam => self.hram[address],
+            Section::InterruptEnable => self.interrupt_enable,
         }
     }
 
-    pub fn get_word(&self, address: u16) -> Result<i16, &'static str> {
-        let l_data = self.get_byte(address).unwrap();
-        let m_data = self.get_byte(address + 1).unwrap();
+    /// Reads the I/O register at 0xFF00 + `register`.
+    fn read_io(&self, register: u16) -> u8 {
+        match register {
+            0x00 => self.joypad.read(),
+            0x04..=0x07 => self.timer.read_register(register - 0x04),
+            // Only the five lower bits of IF exist, the others read as 1.
+            0x0F => self.interrupt_flag | 0xE0,
+            0x10..=0x3F => self.apu.read_register(register - 0x10),
+            0x46 => self.dma_register,
+            0x40..=0x4B => self.ppu.read_register(register - 0x40),
+            0x4D => self.speed_switch | 0x7E,
+            _ => self.io[register as usize],
+        }
+    }
 
-        let data = (((m_data as u16) << 8) | (l_data as u8) as u16) as i16;
+    pub fn get_word(&self, address: u16) -> u16 {
+        let l_data = self.get_byte(address);
+        let m_data = self.get_byte(address.wrapping_add(1));
 
-        Ok(data)
+        (m_data as u16) << 8 | l_data as u16
     }
 
-    pub fn set_byte(&mut self, address: u16, value: i8) {
-        let translate_address = Memory::translate_address(address).unwrap();
+    pub fn set_byte(&mut self, address: u16, value: u8) {
+        if self.dma_blocks(address) {
+            return;
+        }
+
+        let translate_address = Memory::translate_address(address);
+        let address = translate_address.address as usize;
         match translate_address.section {
-            Section::Rom => {
-                self.rom[translate_address.address as usize] = value as u8
-            }
-            Section::Ram => {
-                self.ram[translate_address.address as usize] = value as u8
+            Section::Rom => self.cartridge.write_rom(address as u16, value),
+            Section::VideoRam if !self.ppu.vram_accessible() => {}
+            Section::VideoRam => self.ppu.write_vram(address as u16, value),
+            Section::ExternalRam => {
+                self.cartridge.write_ram(address as u16, value)
             }
+            Section::Ram => self.ram[address] = value,
+            Section::Oam if !self.ppu.oam_accessible() => {}
+            Section::Oam => self.ppu.write_oam(address as u16, value),
+            Section::Unusable => {}
+            Section::Io => self.write_io(address as u16, value),
+            Section::HighRam => self.hram[address] = value,
+            Section::InterruptEnable => self.interrupt_enable = value,
         };
     }
 
-    pub fn set_word(&mut self, address: u16, value: i16) {
-        let translate_address = Memory::translate_address(address).unwrap();
-        match translate_address.section {
-            Section::Rom => {
-                self.rom[translate_address.address as usize] =
-                    (value >> 8) as u8;
-                self.rom[translate_address.address as usize] = value as u8;
-            }
-            Section::Ram => {
-                self.ram[translate_address.address as usize] =
-                    (value >> 8) as u8;
-                self.ram[translate_address.address as usize] = value as u8;
-            }
+    /// Writes the I/O register at 0xFF00 + `register`.
+    fn write_io(&mut self, register: u16, value: u8) {
+        match register {
+            0x00 => self.interrupt_flag |= self.joypad.write(value),
+            0x04..=0x07 => self.timer.write_register(register - 0x04, value),
+            0x0F => self.interrupt_flag = value & 0x1F,
+            0x10..=0x3F => self.apu.write_register(register - 0x10, value),
+            0x46 => self.start_dma(value),
+            0x40..=0x4B => self.ppu.write_register(register - 0x40, value),
+            // Only the prepare bit is writable, the speed comes from STOP.
+            0x4D => self.speed_switch = self.speed_switch & 0x80 | value & 0x01,
+            _ => self.io[register as usize] = value,
+        }
+    }
+
+    pub fn set_word(&mut self, address: u16, value: u16) {
+        self.set_byte(address, value as u8);
+        self.set_byte(address.wrapping_add(1), (value >> 8) as u8);
+    }
+
+    pub fn cartridge_mut(&mut self) -> &mut Cartridge {
+        &mut self.cartridge
+    }
+
+    pub fn ppu(&self) -> &Ppu {
+        &self.ppu
+    }
+
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        &mut self.apu
+    }
+
+    /// Copies 0xA0 bytes from `value` * 0x100 to the OAM. A write while a
+    /// transfer runs restarts it.
+    fn start_dma(&mut self, value: u8) {
+        self.dma_register = value;
+        // Sources past the work RAM read from its echo.
+        let source = (value as u16) << 8;
+        let source = if source >= 0xE000 {
+            source - 0x2000
+        } else {
+            source
+        };
+        self.dma = Some(Dma { source, cycles: 0 });
+    }
+
+    /// While a transfer runs the CPU only reaches the registers and the
+    /// high RAM, the rest of the bus belongs to the DMA.
+    fn dma_blocks(&self, address: u16) -> bool {
+        self.dma.is_some() && address < 0xFF00
+    }
+
+    /// The DMA copies a byte per M-cycle, after a first M-cycle of setup.
+    fn tick_dma(&mut self, cycles: u32) {
+        let mut dma = match self.dma.take() {
+            Some(dma) => dma,
+            None => return,
+        };
+
+        let copied =
+            |cycles: u32| (cycles.saturating_sub(4) / 4).min(DMA_LENGTH);
+        let start = copied(dma.cycles);
+        dma.cycles += cycles;
+        let end = copied(dma.cycles);
+
+        for index in start..end {
+            let value = self.read(dma.source + index as u16);
+            self.ppu.write_oam(index as u16, value);
+        }
+
+        if end < DMA_LENGTH {
+            self.dma = Some(dma);
+        }
+    }
+
+    /// Lets the components on the bus catch up with the CPU.
+    pub fn tick(&mut self, cycles: u32) {
+        self.tick_dma(cycles);
+        self.interrupt_flag |= self.timer.tick(cycles);
+
+        // In double speed only the CPU, the timer and the DMA go faster, the
+        // rest sees half the cycles, passed on whole M-cycles at a time.
+        let cycles = if self.double_speed() {
+            self.double_speed_cycles += cycles;
+            let normal = self.double_speed_cycles / 8 * 4;
+            self.double_speed_cycles %= 8;
+            normal
+        } else {
+            cycles
         };
+        self.cartridge.tick(cycles);
+        self.interrupt_flag |= self.ppu.tick(cycles);
+        self.apu.tick(cycles);
+    }
+
+    pub fn press(&mut self, button: Button) {
+        let interrupts = self.joypad.press(button);
+        self.interrupt_flag |= interrupts;
+        self.joypad_pressed |= interrupts != 0;
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.joypad.release(button);
+    }
+
+    pub fn acknowledge_interrupt(&mut self, interrupt: Interrupt) {
+        self.interrupt_flag &= !interrupt.mask();
+    }
+
+    pub fn pending_interrupt(&self) -> Option<Interrupt> {
+        Interrupt::pending(self.interrupt_enable, self.interrupt_flag)
+    }
+
+    /// Whether a P1 line went low since the last call, which wakes the CPU
+    /// from STOP regardless of IE and IF.
+    pub fn take_joypad_pressed(&mut self) -> bool {
+        mem::replace(&mut self.joypad_pressed, false)
+    }
+
+    pub fn speed_switch_armed(&self) -> bool {
+        self.speed_switch & 0x01 != 0
+    }
+
+    pub fn switch_speed(&mut self) {
+        self.speed_switch = !self.speed_switch & 0x80;
+    }
+
+    pub fn double_speed(&self) -> bool {
+        self.speed_switch & 0x80 != 0
     }
 
-    pub fn new(rom: Vec<u8>) -> Memory {
+    pub fn new(
+        cartridge: Cartridge,
+        renderer: Renderer,
+        sample_rate: u32,
+    ) -> Memory {
         Memory {
-            rom,
+            cartridge,
+            ppu: Ppu::new(renderer),
+            timer: Timer::new(),
+            joypad: Joypad::new(),
+            apu: Apu::new(sample_rate),
             ram: vec![0; 0xE000 - 0xC000],
+            io: vec![0; 0xFF80 - 0xFF00],
+            hram: vec![0; 0xFFFF - 0xFF80],
+            interrupt_flag: 0x01,
+            interrupt_enable: 0x00,
+            speed_switch: 0x00,
+            double_speed_cycles: 0,
+            joypad_pressed: false,
+            dma_register: 0xFF,
+            dma: None,
         }
     }
 }
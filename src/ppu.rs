@@ -0,0 +1,700 @@
+use fifo::{Fifo, ObjectPixel, Step};
+use interrupt::Interrupt;
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+/// T-cycles each line takes, visible or not.
+const CYCLES_PER_LINE: u16 = 456;
+const OAM_SCAN_CYCLES: u16 = 80;
+/// Shortest drawing time, without scrolling, window or sprites.
+const DRAWING_CYCLES: u16 = 172;
+const LINES_PER_FRAME: u8 = 154;
+/// Objects the PPU can show on a single line.
+const SPRITES_PER_LINE: usize = 10;
+
+/// PPU modes, numbered as in the two lower bits of STAT.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Mode {
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Drawing = 3,
+}
+
+/// How lines get drawn during mode 3.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Renderer {
+    /// Draws each line at once, with the registers as they are at the end
+    /// of mode 3.
+    Scanline,
+    /// Pushes pixels one by one through the FIFOs, like the hardware does,
+    /// so registers changed during mode 3 take effect mid line.
+    Fifo,
+}
+
+/// T-cycles the fetcher takes to read a sprite row.
+const SPRITE_FETCH_CYCLES: u8 = 6;
+
+/// Object attributes, as stored in the four bytes of each OAM entry.
+#[derive(Clone, Copy)]
+struct Sprite {
+    /// Vertical position plus 16.
+    y: u8,
+    /// Horizontal position plus 8.
+    x: u8,
+    tile: u8,
+    attributes: u8,
+}
+
+impl Sprite {
+    fn from_oam(oam: &[u8], index: usize) -> Sprite {
+        let entry = &oam[index * 4..index * 4 + 4];
+        Sprite {
+            y: entry[0],
+            x: entry[1],
+            tile: entry[2],
+            attributes: entry[3],
+        }
+    }
+
+    /// Background colors 1 to 3 are drawn over the sprite.
+    fn behind_background(&self) -> bool {
+        self.attributes & 0x80 != 0
+    }
+
+    fn y_flip(&self) -> bool {
+        self.attributes & 0x40 != 0
+    }
+
+    fn x_flip(&self) -> bool {
+        self.attributes & 0x20 != 0
+    }
+
+    /// OBP1 instead of OBP0.
+    fn high_palette(&self) -> bool {
+        self.attributes & 0x10 != 0
+    }
+}
+
+/// Picture processing unit, owning the video RAM, the object attribute
+/// memory and the LCD registers (0xFF40-0xFF4B).
+pub struct Ppu {
+    vram: Vec<u8>,
+    oam: Vec<u8>,
+    /// Shades from 0 (lightest) to 3 (darkest), one byte per pixel.
+    framebuffer: Vec<u8>,
+    lcdc: u8,
+    stat: u8,
+    scy: u8,
+    scx: u8,
+    ly: u8,
+    lyc: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    wy: u8,
+    wx: u8,
+    /// Line of the window to draw next, it only advances on lines that
+    /// actually showed the window.
+    window_line: u8,
+    /// Set once LY matched WY during the frame, the window can only show
+    /// up from then on.
+    window_triggered: bool,
+    mode: Mode,
+    /// T-cycles into the current line.
+    cycles: u16,
+    /// OR of the enabled STAT sources, the interrupt fires when it rises.
+    stat_line: bool,
+    renderer: Renderer,
+    fifo: Fifo,
+    /// Sprites on the current line the FIFO did not reach yet.
+    sprites: Vec<Sprite>,
+    /// Sprite being fetched by the FIFO, with the T-cycles spent on it.
+    sprite_fetch: Option<(Sprite, u8)>,
+}
+
+impl Ppu {
+    pub fn read_vram(&self, address: u16) -> u8 {
+        self.vram[address as usize]
+    }
+
+    pub fn write_vram(&mut self, address: u16, value: u8) {
+        self.vram[address as usize] = value;
+    }
+
+    pub fn read_oam(&self, address: u16) -> u8 {
+        self.oam[address as usize]
+    }
+
+    pub fn write_oam(&mut self, address: u16, value: u8) {
+        self.oam[address as usize] = value;
+    }
+
+    /// Reads the register at 0xFF40 + `register`.
+    pub fn read_register(&self, register: u16) -> u8 {
+        match register {
+            0x0 => self.lcdc,
+            0x1 => {
+                let coincidence = if self.ly == self.lyc { 0x04 } else { 0 };
+                0x80 | self.stat | coincidence | self.mode as u8
+            }
+            0x2 => self.scy,
+            0x3 => self.scx,
+            0x4 => self.ly,
+            0x5 => self.lyc,
+            0x7 => self.bgp,
+            0x8 => self.obp0,
+            0x9 => self.obp1,
+            0xA => self.wy,
+            0xB => self.wx,
+            _ => 0xFF,
+        }
+    }
+
+    /// Writes the register at 0xFF40 + `register`.
+    pub fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            0x0 => {
+                let enabled = self.enabled();
+                self.lcdc = value;
+                if enabled && !self.enabled() {
+                    self.turn_off();
+                } else if !enabled && self.enabled() {
+                    self.set_mode(Mode::OamScan);
+                }
+            }
+            // Mode and coincidence bits are read only.
+            0x1 => self.stat = value & 0x78,
+            0x2 => self.scy = value,
+            0x3 => self.scx = value,
+            // LY is read only.
+            0x4 => {}
+            0x5 => self.lyc = value,
+            0x7 => self.bgp = value,
+            0x8 => self.obp0 = value,
+            0x9 => self.obp1 = value,
+            0xA => self.wy = value,
+            0xB => self.wx = value,
+            _ => {}
+        }
+    }
+
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
+
+    /// The CPU can not reach the VRAM while the PPU draws from it.
+    pub fn vram_accessible(&self) -> bool {
+        self.mode != Mode::Drawing
+    }
+
+    /// The CPU can not reach the OAM while the PPU scans or draws sprites.
+    pub fn oam_accessible(&self) -> bool {
+        self.mode != Mode::OamScan && self.mode != Mode::Drawing
+    }
+
+    /// LCDC bit 7 turns the LCD, and the whole PPU, on and off.
+    fn enabled(&self) -> bool {
+        self.lcdc & 0x80 != 0
+    }
+
+    /// The screen goes blank and LY stays at 0 until the LCD comes back.
+    fn turn_off(&mut self) {
+        self.ly = 0;
+        self.cycles = 0;
+        self.window_line = 0;
+        self.mode = Mode::HBlank;
+        self.stat_line = false;
+        for pixel in self.framebuffer.iter_mut() {
+            *pixel = 0;
+        }
+    }
+
+    fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+        match mode {
+            Mode::OamScan => {
+                if self.ly == 0 {
+                    self.window_triggered = false;
+                }
+                if self.ly == self.wy {
+                    self.window_triggered = true;
+                }
+            }
+            Mode::Drawing => {
+                self.sprites = self.scan_oam();
+                self.sprite_fetch = None;
+                self.fifo.start(self.scx);
+            }
+            _ => {}
+        }
+    }
+
+    /// Runs the PPU for `cycles` T-cycles, returning the IF bits of the
+    /// interrupts it requested meanwhile.
+    pub fn tick(&mut self, cycles: u32) -> u8 {
+        let mut interrupts = 0;
+        if !self.enabled() {
+            return interrupts;
+        }
+
+        for _ in 0..cycles {
+            self.cycles += 1;
+            match self.mode {
+                Mode::OamScan if self.cycles == OAM_SCAN_CYCLES => {
+                    self.set_mode(Mode::Drawing);
+                }
+                Mode::Drawing => self.draw(),
+                Mode::HBlank | Mode::VBlank
+                    if self.cycles == CYCLES_PER_LINE =>
+                {
+                    interrupts |= self.next_line();
+                }
+                _ => {}
+            }
+
+            if self.update_stat_line() {
+                interrupts |= Interrupt::Stat.mask();
+            }
+        }
+        interrupts
+    }
+
+    fn next_line(&mut self) -> u8 {
+        self.cycles = 0;
+        self.ly += 1;
+
+        if self.ly as usize == SCREEN_HEIGHT {
+            self.set_mode(Mode::VBlank);
+            return Interrupt::VBlank.mask();
+        }
+
+        if self.ly == LINES_PER_FRAME {
+            self.ly = 0;
+            self.window_line = 0;
+        }
+        if (self.ly as usize) < SCREEN_HEIGHT {
+            self.set_mode(Mode::OamScan);
+        }
+        0
+    }
+
+    /// Recomputes the STAT interrupt line, returning whether it rose. While
+    /// one source holds the line high, the others can not fire again.
+    fn update_stat_line(&mut self) -> bool {
+        let line = (self.stat & 0x40 != 0 && self.ly == self.lyc)
+            || (self.stat & 0x20 != 0 && self.mode == Mode::OamScan)
+            || (self.stat & 0x10 != 0 && self.mode == Mode::VBlank)
+            || (self.stat & 0x08 != 0 && self.mode == Mode::HBlank);
+
+        let rose = line && !self.stat_line;
+        self.stat_line = line;
+        rose
+    }
+
+    /// Spends a T-cycle of mode 3, moving on to HBlank once the line is
+    /// done.
+    fn draw(&mut self) {
+        match self.renderer {
+            Renderer::Scanline => {
+                if self.cycles < OAM_SCAN_CYCLES + DRAWING_CYCLES {
+                    return;
+                }
+                self.render_line();
+            }
+            Renderer::Fifo => {
+                self.fifo_cycle();
+                if (self.fifo.x as usize) < SCREEN_WIDTH {
+                    return;
+                }
+                if self.fifo.window {
+                    self.window_line += 1;
+                }
+            }
+        }
+        self.set_mode(Mode::HBlank);
+    }
+
+    fn fifo_cycle(&mut self) {
+        if self.sprite_fetch.is_none() && self.fifo.discard == 0 {
+            self.find_sprite();
+        }
+
+        if let Some((sprite, cycles)) = self.sprite_fetch {
+            self.fetch_sprite(sprite, cycles);
+            return;
+        }
+
+        if self.window_starts() {
+            self.fifo.start_window();
+            return;
+        }
+
+        self.fetcher_cycle();
+        if self.fifo.step == Step::Push {
+            self.fifo.push();
+        }
+        self.shift_pixel();
+    }
+
+    /// Starts fetching the next sprite that begins on the current pixel.
+    fn find_sprite(&mut self) {
+        if self.lcdc & 0x02 == 0 {
+            return;
+        }
+
+        // Sprites partly off the left edge all start on the first pixel.
+        // The first one fetched wins, so the smallest X goes first, then the
+        // lowest OAM index.
+        let x = self.fifo.x as u16 + 8;
+        let found = self
+            .sprites
+            .iter()
+            .enumerate()
+            .filter(|&(_, sprite)| sprite.x as u16 <= x)
+            .min_by_key(|&(index, sprite)| (sprite.x, index))
+            .map(|(index, _)| index);
+        if let Some(index) = found {
+            let sprite = self.sprites.remove(index);
+            self.sprite_fetch = Some((sprite, 0));
+        }
+    }
+
+    /// The background fetch in progress completes before the sprite gets
+    /// fetched, while no pixel goes out.
+    fn fetch_sprite(&mut self, sprite: Sprite, cycles: u8) {
+        if self.fifo.step != Step::Push {
+            self.fetcher_cycle();
+            return;
+        }
+
+        let cycles = cycles + 1;
+        if cycles < SPRITE_FETCH_CYCLES {
+            self.sprite_fetch = Some((sprite, cycles));
+            return;
+        }
+
+        let skip = if sprite.x < 8 {
+            8 - sprite.x as usize
+        } else {
+            0
+        };
+        let template = ObjectPixel {
+            color: 0,
+            high_palette: sprite.high_palette(),
+            behind_background: sprite.behind_background(),
+        };
+        self.fifo
+            .merge_sprite(self.sprite_row(&sprite), skip, template);
+        self.sprite_fetch = None;
+    }
+
+    fn window_starts(&self) -> bool {
+        !self.fifo.window
+            && self.lcdc & 0x20 != 0
+            && self.window_triggered
+            && self.fifo.x as u16 + 7 >= self.wx as u16
+    }
+
+    fn fetcher_cycle(&mut self) {
+        match self.fifo.step {
+            Step::Tile => {
+                if self.fifo.advance(Step::DataLow) {
+                    self.fifo.tile = self.fetch_tile();
+                }
+            }
+            Step::DataLow => {
+                if self.fifo.advance(Step::DataHigh) {
+                    self.fifo.low = self.vram[self.fetch_row()];
+                }
+            }
+            Step::DataHigh => {
+                if self.fifo.advance(Step::Push) {
+                    self.fifo.high = self.vram[self.fetch_row() + 1];
+                }
+            }
+            Step::Push => {}
+        }
+    }
+
+    /// Tile number under the fetcher, with the registers as they are now.
+    fn fetch_tile(&self) -> u8 {
+        let (map, x, y) = if self.fifo.window {
+            (self.window_map(), self.fifo.tile_x, self.window_line)
+        } else {
+            (
+                self.background_map(),
+                (self.scx / 8).wrapping_add(self.fifo.tile_x),
+                self.ly.wrapping_add(self.scy),
+            )
+        };
+        self.vram[map + (y as usize / 8) * 32 + (x & 0x1F) as usize]
+    }
+
+    /// Address of the low byte of the row the fetcher is on.
+    fn fetch_row(&self) -> usize {
+        let y = if self.fifo.window {
+            self.window_line
+        } else {
+            self.ly.wrapping_add(self.scy)
+        };
+        self.tile_address(self.fifo.tile) + (y % 8) as usize * 2
+    }
+
+    /// Sends a pixel to the LCD, mixing both FIFOs with the palettes as
+    /// they are now.
+    fn shift_pixel(&mut self) {
+        let color = match self.fifo.background.pop_front() {
+            Some(color) => color,
+            None => return,
+        };
+        if self.fifo.discard > 0 {
+            self.fifo.discard -= 1;
+            return;
+        }
+
+        let color = if self.background_enabled() { color } else { 0 };
+        let shade = match self.fifo.objects.pop_front() {
+            Some(pixel)
+                if pixel.color != 0
+                    && self.lcdc & 0x02 != 0
+                    && !(pixel.behind_background && color != 0) =>
+            {
+                let palette = if pixel.high_palette {
+                    self.obp1
+                } else {
+                    self.obp0
+                };
+                shade(palette, pixel.color)
+            }
+            _ => shade(self.bgp, color),
+        };
+
+        let x = self.fifo.x as usize;
+        self.framebuffer[self.ly as usize * SCREEN_WIDTH + x] = shade;
+        self.fifo.x += 1;
+    }
+
+    fn render_line(&mut self) {
+        let mut colors = [0u8; SCREEN_WIDTH];
+        self.render_background(&mut colors);
+        self.render_window(&mut colors);
+
+        let mut shades = [0u8; SCREEN_WIDTH];
+        for (pixel, color) in shades.iter_mut().zip(colors.iter()) {
+            *pixel = shade(self.bgp, *color);
+        }
+        self.render_sprites(&colors, &mut shades);
+
+        let line = self.ly as usize * SCREEN_WIDTH;
+        self.framebuffer[line..line + SCREEN_WIDTH].copy_from_slice(&shades);
+    }
+
+    /// Without LCDC bit 0 neither the background nor the window show up,
+    /// leaving the line in color 0.
+    fn background_enabled(&self) -> bool {
+        self.lcdc & 0x01 != 0
+    }
+
+    fn background_map(&self) -> usize {
+        if self.lcdc & 0x08 != 0 {
+            0x1C00
+        } else {
+            0x1800
+        }
+    }
+
+    fn window_map(&self) -> usize {
+        if self.lcdc & 0x40 != 0 {
+            0x1C00
+        } else {
+            0x1800
+        }
+    }
+
+    fn render_background(&self, colors: &mut [u8; SCREEN_WIDTH]) {
+        if !self.background_enabled() {
+            return;
+        }
+
+        let map = self.background_map();
+        let y = self.ly.wrapping_add(self.scy);
+        for (x, color) in colors.iter_mut().enumerate() {
+            let x = (x as u8).wrapping_add(self.scx);
+            *color = self.map_color(map, x, y);
+        }
+    }
+
+    fn render_window(&mut self, colors: &mut [u8; SCREEN_WIDTH]) {
+        if !self.background_enabled()
+            || self.lcdc & 0x20 == 0
+            || self.ly < self.wy
+            || self.wx > 166
+        {
+            return;
+        }
+
+        // WX holds the window position plus 7.
+        let map = self.window_map();
+        let start = self.wx as i16 - 7;
+        for (x, color) in colors.iter_mut().enumerate() {
+            let x = x as i16 - start;
+            if x >= 0 {
+                *color = self.map_color(map, x as u8, self.window_line);
+            }
+        }
+        self.window_line += 1;
+    }
+
+    fn sprite_height(&self) -> u8 {
+        if self.lcdc & 0x04 != 0 {
+            16
+        } else {
+            8
+        }
+    }
+
+    /// First sprites in OAM order that cross the current line, as many as
+    /// the hardware picks during the OAM scan.
+    fn scan_oam(&self) -> Vec<Sprite> {
+        let height = self.sprite_height();
+        let ly = self.ly as u16 + 16;
+        (0..40)
+            .map(|index| Sprite::from_oam(&self.oam, index))
+            .filter(|sprite| {
+                sprite.y as u16 <= ly && ly < sprite.y as u16 + height as u16
+            })
+            .take(SPRITES_PER_LINE)
+            .collect()
+    }
+
+    /// Color numbers of the sprite row on the current line, from left to
+    /// right, 0 being transparent.
+    fn sprite_row(&self, sprite: &Sprite) -> [u8; 8] {
+        let height = self.sprite_height();
+        let mut row = (self.ly + 16 - sprite.y) % height;
+        if sprite.y_flip() {
+            row = height - 1 - row;
+        }
+
+        // 8x16 sprites ignore the lowest bit of the tile number.
+        let tile = if height == 16 {
+            sprite.tile & 0xFE
+        } else {
+            sprite.tile
+        };
+
+        let mut colors = [0; 8];
+        for (column, color) in colors.iter_mut().enumerate() {
+            *color = self.tile_color(tile as usize * 16, column as u8, row);
+        }
+        if sprite.x_flip() {
+            colors.reverse();
+        }
+        colors
+    }
+
+    /// Color number of the sprite at column `x` of the screen.
+    fn sprite_color(&self, sprite: &Sprite, x: u8) -> u8 {
+        self.sprite_row(sprite)[(x + 8 - sprite.x) as usize]
+    }
+
+    fn render_sprites(
+        &self,
+        colors: &[u8; SCREEN_WIDTH],
+        shades: &mut [u8; SCREEN_WIDTH],
+    ) {
+        if self.lcdc & 0x02 == 0 {
+            return;
+        }
+
+        // The leftmost sprite wins, then the first one in OAM. The sort is
+        // stable, so sprites on the same column keep their OAM order.
+        let mut sprites = self.scan_oam();
+        sprites.sort_by_key(|sprite| sprite.x);
+
+        for (x, pixel) in shades.iter_mut().enumerate() {
+            let x = x as u8;
+            let opaque = sprites
+                .iter()
+                .filter(|sprite| {
+                    let left = sprite.x as i16 - 8;
+                    left <= x as i16 && (x as i16) < left + 8
+                })
+                .map(|sprite| (sprite, self.sprite_color(sprite, x)))
+                .find(|&(_, color)| color != 0);
+
+            if let Some((sprite, color)) = opaque {
+                if sprite.behind_background() && colors[x as usize] != 0 {
+                    continue;
+                }
+                let palette = if sprite.high_palette() {
+                    self.obp1
+                } else {
+                    self.obp0
+                };
+                *pixel = shade(palette, color);
+            }
+        }
+    }
+
+    /// Color number of the pixel at (`x`, `y`) of the 256x256 tile map.
+    fn map_color(&self, map: usize, x: u8, y: u8) -> u8 {
+        let index = map + (y as usize / 8) * 32 + x as usize / 8;
+        let tile = self.vram[index];
+        self.tile_color(self.tile_address(tile), x % 8, y % 8)
+    }
+
+    /// With LCDC bit 4 tiles are numbered from 0x8000, otherwise they are
+    /// signed and numbered from 0x9000.
+    fn tile_address(&self, tile: u8) -> usize {
+        if self.lcdc & 0x10 != 0 {
+            tile as usize * 16
+        } else {
+            (0x1000 + (tile as i8 as isize) * 16) as usize
+        }
+    }
+
+    /// Tiles have two bytes per row, holding the low and the high bits of
+    /// the color numbers, with the leftmost pixel in bit 7.
+    fn tile_color(&self, tile_address: usize, x: u8, y: u8) -> u8 {
+        let row = tile_address + y as usize * 2;
+        let low = self.vram[row] >> (7 - x) & 0x01;
+        let high = self.vram[row + 1] >> (7 - x) & 0x01;
+        high << 1 | low
+    }
+
+    pub fn new(renderer: Renderer) -> Ppu {
+        Ppu {
+            vram: vec![0; 0x2000],
+            oam: vec![0; 0xA0],
+            framebuffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            lcdc: 0x91,
+            stat: 0x00,
+            scy: 0x00,
+            scx: 0x00,
+            ly: 0x00,
+            lyc: 0x00,
+            bgp: 0xFC,
+            obp0: 0xFF,
+            obp1: 0xFF,
+            wy: 0x00,
+            wx: 0x00,
+            window_line: 0,
+            window_triggered: false,
+            mode: Mode::OamScan,
+            cycles: 0,
+            stat_line: false,
+            renderer,
+            fifo: Fifo::new(),
+            sprites: Vec::with_capacity(SPRITES_PER_LINE),
+            sprite_fetch: None,
+        }
+    }
+}
+
+/// Maps a color number to a shade through a palette register.
+fn shade(palette: u8, color: u8) -> u8 {
+    palette >> (color * 2) & 0x03
+}
@@ -0,0 +1,197 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use cpu::CYCLES_PER_SECOND;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Clock {
+    /// Follows the wall clock, even while the emulator is closed.
+    Host,
+    /// Follows the cycles run by the CPU.
+    Emulated,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Registers {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    day_low: u8,
+    /// Bit 0 is the day bit 8, bit 6 halts the clock and bit 7 is the day
+    /// counter carry.
+    day_high: u8,
+}
+
+impl Registers {
+    fn read(&self, register: u8) -> u8 {
+        match register {
+            0x08 => self.seconds,
+            0x09 => self.minutes,
+            0x0A => self.hours,
+            0x0B => self.day_low,
+            _ => self.day_high | 0x3E,
+        }
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        match register {
+            0x08 => self.seconds = value & 0x3F,
+            0x09 => self.minutes = value & 0x3F,
+            0x0A => self.hours = value & 0x1F,
+            0x0B => self.day_low = value,
+            _ => self.day_high = value & 0xC1,
+        }
+    }
+
+    fn append_to(&self, bytes: &mut Vec<u8>) {
+        for value in &[
+            self.seconds,
+            self.minutes,
+            self.hours,
+            self.day_low,
+            self.day_high,
+        ] {
+            bytes.extend_from_slice(&[*value, 0, 0, 0]);
+        }
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Registers {
+        Registers {
+            seconds: bytes[0],
+            minutes: bytes[4],
+            hours: bytes[8],
+            day_low: bytes[12],
+            day_high: bytes[16],
+        }
+    }
+}
+
+/// MBC3 real time clock, registers 0x08 to 0x0C.
+pub struct Rtc {
+    clock: Clock,
+    live: Registers,
+    latched: Registers,
+    /// Last value written to the latch register, latching happens on 0 -> 1.
+    latch: u8,
+    /// Unix time the live registers were last brought up to date.
+    updated_at: u64,
+    cycles: u32,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+impl Rtc {
+    pub fn new(clock: Clock) -> Rtc {
+        Rtc {
+            clock,
+            live: Registers::default(),
+            latched: Registers::default(),
+            latch: 0xFF,
+            updated_at: now(),
+            cycles: 0,
+        }
+    }
+
+    fn halted(&self) -> bool {
+        self.live.day_high & 0x40 != 0
+    }
+
+    fn advance(&mut self, seconds: u64) {
+        if self.halted() || seconds == 0 {
+            return;
+        }
+
+        let live = &mut self.live;
+        let mut carry = live.seconds as u64 + seconds;
+        live.seconds = (carry % 60) as u8;
+        carry = carry / 60 + live.minutes as u64;
+        live.minutes = (carry % 60) as u8;
+        carry = carry / 60 + live.hours as u64;
+        live.hours = (carry % 24) as u8;
+        carry = carry / 24
+            + ((live.day_high as u64 & 0x01) << 8 | live.day_low as u64);
+        live.day_low = carry as u8;
+        live.day_high = live.day_high & 0xFE | (carry >> 8) as u8 & 0x01;
+        if carry > 0x1FF {
+            live.day_high |= 0x80;
+        }
+    }
+
+    /// Brings the live registers up to date with the host clock.
+    fn update(&mut self) {
+        if self.clock == Clock::Host {
+            let now = now();
+            let elapsed = now.saturating_sub(self.updated_at);
+            self.advance(elapsed);
+            self.updated_at = now;
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        if self.clock == Clock::Emulated {
+            self.cycles += cycles;
+            while self.cycles >= CYCLES_PER_SECOND {
+                self.cycles -= CYCLES_PER_SECOND;
+                self.advance(1);
+            }
+        }
+    }
+
+    pub fn write_latch(&mut self, value: u8) {
+        if self.latch == 0x00 && value == 0x01 {
+            self.update();
+            self.latched = self.live;
+        }
+        self.latch = value;
+    }
+
+    pub fn read(&self, register: u8) -> u8 {
+        self.latched.read(register)
+    }
+
+    pub fn write(&mut self, register: u8, value: u8) {
+        self.update();
+        // Writing the seconds resets the sub-second divider.
+        if register == 0x08 {
+            self.cycles = 0;
+        }
+        self.live.write(register, value);
+        self.latched.write(register, value);
+    }
+
+    /// State in the 48 bytes footer appended to `.sav` files by most
+    /// emulators: live and latched registers as 32 bits words, followed by
+    /// a 64 bits unix timestamp, all little endian.
+    pub fn save(&mut self) -> Vec<u8> {
+        self.update();
+
+        let mut bytes = Vec::with_capacity(48);
+        self.live.append_to(&mut bytes);
+        self.latched.append_to(&mut bytes);
+        for shift in 0..8 {
+            bytes.push((self.updated_at >> (shift * 8)) as u8);
+        }
+        bytes
+    }
+
+    /// Restores a footer written by `save`, also accepting the older 44
+    /// bytes variant with a 32 bits timestamp.
+    pub fn load(&mut self, bytes: &[u8]) -> Result<(), &'static str> {
+        if bytes.len() != 44 && bytes.len() != 48 {
+            return Err("RTC footer must have 44 or 48 bytes");
+        }
+
+        self.live = Registers::from_bytes(&bytes[0..20]);
+        self.latched = Registers::from_bytes(&bytes[20..40]);
+        self.updated_at = bytes[40..]
+            .iter()
+            .rev()
+            .fold(0, |timestamp, byte| timestamp << 8 | *byte as u64);
+        self.update();
+        Ok(())
+    }
+}
@@ -0,0 +1,58 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use cartridge::Cartridge;
+
+/// Battery backed cartridge RAM kept in a raw `.sav` file, the format most
+/// emulators share.
+pub struct SaveFile {
+    path: PathBuf,
+}
+
+impl SaveFile {
+    pub fn new<P: Into<PathBuf>>(path: P) -> SaveFile {
+        SaveFile { path: path.into() }
+    }
+
+    /// The `.sav` file next to the ROM, with the same name.
+    pub fn beside(rom_path: &Path) -> SaveFile {
+        SaveFile::new(rom_path.with_extension("sav"))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Fills the cartridge RAM from the file, if there is one yet.
+    pub fn load(&self, cartridge: &mut Cartridge) -> io::Result<()> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(())
+            }
+            Err(err) => return Err(err),
+        };
+
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        cartridge
+            .load(&bytes)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// Writes the cartridge RAM, and clock, if they changed since the last
+    /// flush. The file is replaced at once, so a crash never leaves half a
+    /// save behind.
+    pub fn flush(&self, cartridge: &mut Cartridge) -> io::Result<()> {
+        if !cartridge.dirty() {
+            return Ok(());
+        }
+
+        let temporary = self.path.with_extension("sav.tmp");
+        let mut file = File::create(&temporary)?;
+        file.write_all(&cartridge.save())?;
+        file.sync_all()?;
+        fs::rename(&temporary, &self.path)
+    }
+}
@@ -0,0 +1,126 @@
+use interrupt::Interrupt;
+
+/// State of TIMA after it overflows.
+#[derive(Clone, Copy, PartialEq)]
+enum Reload {
+    None,
+    /// TIMA reads 0 for an M-cycle before getting TMA, a write to TIMA
+    /// meanwhile cancels the reload.
+    Overflowed,
+    /// TIMA gets TMA, writes to TIMA are ignored and writes to TMA go
+    /// through to TIMA.
+    Reloading,
+}
+
+/// DIV, TIMA, TMA and TAC (0xFF04-0xFF07).
+pub struct Timer {
+    /// Internal 16 bits counter, DIV being its upper byte.
+    counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    reload: Reload,
+}
+
+impl Timer {
+    /// Reads the register at 0xFF04 + `register`.
+    pub fn read_register(&self, register: u16) -> u8 {
+        match register {
+            0x0 => (self.counter >> 8) as u8,
+            0x1 => self.tima,
+            0x2 => self.tma,
+            _ => self.tac | 0xF8,
+        }
+    }
+
+    /// Writes the register at 0xFF04 + `register`. Writes to DIV and TAC
+    /// can bring the selected bit down, which increments TIMA.
+    pub fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            0x0 => {
+                let signal = self.signal();
+                self.counter = 0;
+                self.detect_edge(signal);
+            }
+            0x1 => match self.reload {
+                Reload::None => self.tima = value,
+                Reload::Overflowed => {
+                    self.tima = value;
+                    self.reload = Reload::None;
+                }
+                Reload::Reloading => {}
+            },
+            0x2 => {
+                self.tma = value;
+                if self.reload == Reload::Reloading {
+                    self.tima = value;
+                }
+            }
+            _ => {
+                let signal = self.signal();
+                self.tac = value & 0x07;
+                self.detect_edge(signal);
+            }
+        }
+    }
+
+    /// Bit of the counter selected by TAC, ANDed with the enable bit. TIMA
+    /// increments when it goes from 1 to 0.
+    fn signal(&self) -> bool {
+        let bit = match self.tac & 0x03 {
+            0 => 9,
+            1 => 3,
+            2 => 5,
+            _ => 7,
+        };
+        self.tac & 0x04 != 0 && self.counter >> bit & 0x01 != 0
+    }
+
+    fn detect_edge(&mut self, previous: bool) {
+        if previous && !self.signal() {
+            self.increment();
+        }
+    }
+
+    fn increment(&mut self) {
+        if self.tima == 0xFF {
+            self.tima = 0;
+            self.reload = Reload::Overflowed;
+        } else {
+            self.tima += 1;
+        }
+    }
+
+    /// Runs the timer for `cycles` T-cycles, returning the IF bits of the
+    /// interrupts it requested meanwhile.
+    pub fn tick(&mut self, cycles: u32) -> u8 {
+        let mut interrupts = 0;
+        for _ in 0..cycles / 4 {
+            match self.reload {
+                Reload::Overflowed => {
+                    self.tima = self.tma;
+                    self.reload = Reload::Reloading;
+                    interrupts |= Interrupt::Timer.mask();
+                }
+                Reload::Reloading => self.reload = Reload::None,
+                Reload::None => {}
+            }
+
+            let signal = self.signal();
+            self.counter = self.counter.wrapping_add(4);
+            self.detect_edge(signal);
+        }
+        interrupts
+    }
+
+    pub fn new() -> Timer {
+        Timer {
+            // DIV as the DMG boot ROM leaves it.
+            counter: 0xABCC,
+            tima: 0x00,
+            tma: 0x00,
+            tac: 0x00,
+            reload: Reload::None,
+        }
+    }
+}
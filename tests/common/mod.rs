@@ -0,0 +1,20 @@
+use gb_emulator::cartridge::Header;
+
+const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83,
+    0x00, 0x0C, 0x00, 0x0D, 0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E,
+    0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99, 0xBB, 0xBB, 0x67, 0x63,
+    0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+/// 32 KiB ROM with a valid header that jumps to `code`, placed at 0x0150.
+pub fn rom(title: &[u8], code: &[u8]) -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    // nop; jp 0x0150
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+    rom[0x104..0x134].copy_from_slice(&NINTENDO_LOGO);
+    rom[0x134..0x134 + title.len()].copy_from_slice(title);
+    rom[0x14D] = Header::compute_header_checksum(&rom);
+    rom[0x150..0x150 + code.len()].copy_from_slice(code);
+    rom
+}
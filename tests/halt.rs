@@ -0,0 +1,41 @@
+extern crate gb_emulator;
+
+mod common;
+
+use gb_emulator::cartridge::Cartridge;
+use gb_emulator::cpu::CPU;
+use gb_emulator::memory::Memory;
+use gb_emulator::ppu::Renderer;
+use gb_emulator::rtc::Clock;
+
+#[test]
+fn halt_bug_returns_to_the_halt() {
+    let mut rom = common::rom(
+        b"HALT",
+        &[
+            0x3E, 0x01, // ld a, 1
+            0xE0, 0xFF, // ldh (IE), a
+            0xE0, 0x0F, // ldh (IF), a
+            0xAF, // xor a
+            0xFB, // ei
+            0x76, // halt
+            0x18, 0xFD, // jr -3
+        ],
+    );
+    rom[0x40..0x44].copy_from_slice(&[
+        0x3C, // inc a
+        0xE0, 0x80, // ldh (80), a
+        0xD9, // reti
+    ]);
+
+    let cartridge = Cartridge::new(rom, Clock::Emulated).unwrap();
+    let mut cpu = CPU::new(Memory::new(cartridge, Renderer::Scanline, 48_000));
+    // Far from the first VBlank, which would run the handler again.
+    for _ in 0..100 {
+        cpu.step();
+    }
+
+    // With the interrupt pending, EI; HALT must run the handler once and
+    // not repeat its first instruction.
+    assert_eq!(cpu.memory().get_byte(0xFF80), 1);
+}
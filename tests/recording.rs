@@ -0,0 +1,72 @@
+extern crate gb_emulator;
+
+mod common;
+
+use std::env;
+use std::fs;
+
+use gb_emulator::audio::Recorder;
+use gb_emulator::cartridge::Cartridge;
+use gb_emulator::cpu::CPU;
+use gb_emulator::memory::Memory;
+use gb_emulator::ppu::Renderer;
+use gb_emulator::rtc::Clock;
+
+/// ROM that starts a tone on channel 1 and noise on channel 4, then spins.
+fn tone_rom() -> Vec<u8> {
+    let mut code = Vec::new();
+    for &(register, value) in &[
+        (0x26, 0x80), // NR52, power on
+        (0x24, 0x77), // NR50
+        (0x25, 0xFF), // NR51
+        (0x11, 0x80), // NR11, 50% duty
+        (0x12, 0xF0), // NR12
+        (0x13, 0x00), // NR13
+        (0x14, 0x87), // NR14, trigger
+        (0x21, 0xF0), // NR42
+        (0x22, 0x55), // NR43
+        (0x23, 0x80), // NR44, trigger
+    ] {
+        // ld a, value; ldh (register), a
+        code.extend_from_slice(&[0x3E, value, 0xE0, register]);
+    }
+    // jr -2
+    code.extend_from_slice(&[0x18, 0xFE]);
+    common::rom(b"TONE", &code)
+}
+
+/// Runs the ROM for a fixed number of cycles, playing at `sample_rate`
+/// and recording at 48 kHz, and returns the recorded WAV file.
+fn record(name: &str, sample_rate: f64) -> Vec<u8> {
+    let cartridge = Cartridge::new(tone_rom(), Clock::Emulated).unwrap();
+    let mut cpu = CPU::new(Memory::new(cartridge, Renderer::Scanline, 48_000));
+    cpu.memory_mut().apu_mut().set_sample_rate(sample_rate);
+
+    let path = env::temp_dir().join(name);
+    let mut recorder = Recorder::create(&path, 48_000, false).unwrap();
+    cpu.memory_mut().apu_mut().record(recorder.recording());
+
+    while cpu.cycles() < 30 * 70_224 {
+        cpu.step();
+        let apu = cpu.memory_mut().apu_mut();
+        apu.take_samples();
+        recorder.write(apu.recording_mut().unwrap()).unwrap();
+    }
+    recorder.finish().unwrap();
+
+    let bytes = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    bytes
+}
+
+#[test]
+fn recording_is_deterministic() {
+    let first = record("gb_emulator_recording_1.wav", 48_000.0);
+    // The playback rate drifts with the host, the recording must not.
+    let second = record("gb_emulator_recording_2.wav", 47_850.5);
+
+    // 30 frames are half a second, 4 bytes per stereo frame.
+    assert!(first.len() > 44 + 24_000 * 4);
+    assert!(first[44..].iter().any(|byte| *byte != 0));
+    assert!(first == second);
+}